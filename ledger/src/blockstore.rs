@@ -95,7 +95,7 @@ pub use {
         blockstore_meta::{OptimisticSlotMetaVersioned, SlotMeta},
         blockstore_metrics::BlockstoreInsertionMetrics,
     },
-    blockstore_purge::PurgeType,
+    blockstore_purge::{CompactionColumn, PurgeType},
     rocksdb::properties as RocksProperties,
 };
 
@@ -4207,6 +4207,19 @@ impl Blockstore {
             .get_int_property(RocksProperties::TOTAL_SST_FILES_SIZE)
     }
 
+    /// Returns the total size of the SST files of each column, keyed by
+    /// column name.
+    ///
+    /// Note that the reported sizes do not include recently inserted data
+    /// that is still in memory.
+    pub fn column_storage_sizes(&self) -> Result<HashMap<String, u64>> {
+        let mut sizes = HashMap::new();
+        for live_file in self.live_files_metadata()? {
+            *sizes.entry(live_file.column_family_name).or_default() += live_file.size as u64;
+        }
+        Ok(sizes)
+    }
+
     /// Returns whether the blockstore has primary (read and write) access
     pub fn is_primary_access(&self) -> bool {
         self.db.is_primary_access()
@@ -1,6 +1,6 @@
 use {
     super::*,
-    crate::blockstore_db::ColumnIndexDeprecation,
+    crate::blockstore_db::{ColumnIndexDeprecation, ColumnName},
     solana_sdk::message::AccountKeys,
     std::{cmp::max, time::Instant},
 };
@@ -23,6 +23,24 @@ pub enum PurgeType {
     CompactionFilter,
 }
 
+/// The slot-keyed columns that `Blockstore::compact_slots` can compact.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CompactionColumn {
+    ShredData,
+    ShredCode,
+    SlotMeta,
+    Index,
+    ErasureMeta,
+    MerkleRootMeta,
+    Rewards,
+}
+
+impl CompactionColumn {
+    /// The data and coding shred columns, which hold most of the space
+    /// freed by a purge.
+    pub const SHREDS: [Self; 2] = [Self::ShredData, Self::ShredCode];
+}
+
 impl Blockstore {
     /// Performs cleanup based on the specified deletion range.  After this
     /// function call, entries within \[`from_slot`, `to_slot`\] will become
@@ -40,6 +58,23 @@ impl Blockstore {
     /// `AddressSignature`, and `cf::TransactionStatusIndex`, are cleaned-up
     /// based on the `purge_type` setting.
     pub fn purge_slots(&self, from_slot: Slot, to_slot: Slot, purge_type: PurgeType) {
+        if let Err(e) = self.try_purge_slots(from_slot, to_slot, purge_type) {
+            error!(
+                "Error: {:?}; Purge failed in range {:?} to {:?}",
+                e, from_slot, to_slot
+            );
+        }
+    }
+
+    /// Same as `purge_slots`, but returns the error if the purge failed so
+    /// that the caller can retry it. Part of the range may have been purged
+    /// regardless.
+    pub fn try_purge_slots(
+        &self,
+        from_slot: Slot,
+        to_slot: Slot,
+        purge_type: PurgeType,
+    ) -> Result<()> {
         let mut purge_stats = PurgeStats::default();
         let purge_result =
             self.run_purge_with_stats(from_slot, to_slot, purge_type, &mut purge_stats);
@@ -56,12 +91,7 @@ impl Blockstore {
                 i64
             )
         );
-        if let Err(e) = purge_result {
-            error!(
-                "Error: {:?}; Purge failed in range {:?} to {:?}",
-                e, from_slot, to_slot
-            );
-        }
+        purge_result.map(|_| ())
     }
 
     /// Usually this is paired with .purge_slots() but we can't internally call this in
@@ -81,10 +111,54 @@ impl Blockstore {
         }
     }
 
+    /// Returns the highest slot last passed to `set_max_expired_slot`, below
+    /// which the compaction filters drop data, or `None` if no slot has
+    /// expired yet.
+    pub fn max_expired_slot(&self) -> Option<Slot> {
+        self.db.oldest_slot().checked_sub(1)
+    }
+
     pub fn purge_and_compact_slots(&self, from_slot: Slot, to_slot: Slot) {
         self.purge_slots(from_slot, to_slot, PurgeType::Exact);
     }
 
+    /// Compacts `columns` over \[`from_slot`, `to_slot`\]. This is typically
+    /// called after the range has been purged so that the space held by the
+    /// purged data is reclaimed right away instead of whenever RocksDB gets
+    /// around to compacting those files. Only the key range of the slots is
+    /// compacted, not the whole column.
+    pub fn compact_slots(&self, from_slot: Slot, to_slot: Slot, columns: &[CompactionColumn]) {
+        for column in columns {
+            match column {
+                CompactionColumn::ShredData => {
+                    self.compact_slots_cf::<cf::ShredData>(from_slot, to_slot)
+                }
+                CompactionColumn::ShredCode => {
+                    self.compact_slots_cf::<cf::ShredCode>(from_slot, to_slot)
+                }
+                CompactionColumn::SlotMeta => {
+                    self.compact_slots_cf::<cf::SlotMeta>(from_slot, to_slot)
+                }
+                CompactionColumn::Index => self.compact_slots_cf::<cf::Index>(from_slot, to_slot),
+                CompactionColumn::ErasureMeta => {
+                    self.compact_slots_cf::<cf::ErasureMeta>(from_slot, to_slot)
+                }
+                CompactionColumn::MerkleRootMeta => {
+                    self.compact_slots_cf::<cf::MerkleRootMeta>(from_slot, to_slot)
+                }
+                CompactionColumn::Rewards => {
+                    self.compact_slots_cf::<cf::Rewards>(from_slot, to_slot)
+                }
+            }
+        }
+    }
+
+    fn compact_slots_cf<C: Column + ColumnName>(&self, from_slot: Slot, to_slot: Slot) {
+        let from_key = C::key(C::as_index(from_slot));
+        let to_key = C::key(C::as_index(to_slot.saturating_add(1)));
+        self.db.compact_range_cf::<C>(&from_key, &to_key);
+    }
+
     /// Ensures that the SlotMeta::next_slots vector for all slots contain no references in the
     /// \[from_slot,to_slot\] range
     ///
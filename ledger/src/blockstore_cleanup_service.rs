@@ -3,19 +3,33 @@
 //! can be done quickly and should have a fairly stable correlation to actual bytes.
 //! Once the shred count (and thus roughly the byte count) reaches a threshold,
 //! the services begins removing data in FIFO order.
+//!
+//! Each cleanup logs its key events on a single line of the form
+//! `ledger_cleanup event=<event> key=value ...`, where `<event>` is one of
+//! `check`, `scan`, `select`, `dry_run`, `veto`, `noop` or `purge`, so that
+//! log pipelines can parse them without matching on prose.
 
 use {
     crate::{
-        blockstore::{Blockstore, PurgeType},
-        blockstore_db::{Result as BlockstoreResult, DATA_SHRED_CF},
+        blockstore::{Blockstore, CompactionColumn, PurgeType},
+        blockstore_db::{columns as cf, ColumnName, Result as BlockstoreResult, DATA_SHRED_CF},
     },
+    crossbeam_channel::{Sender, TrySendError},
     solana_measure::measure::Measure,
-    solana_sdk::clock::{Slot, DEFAULT_MS_PER_SLOT},
+    solana_perf::thread::renice_this_thread,
+    solana_sdk::{
+        clock::{Slot, DEFAULT_MS_PER_SLOT},
+        epoch_schedule::EpochSchedule,
+    },
     std::{
+        collections::{HashSet, VecDeque},
+        fs, io,
+        panic::{self, AssertUnwindSafe},
+        path::Path,
         string::ToString,
         sync::{
-            atomic::{AtomicBool, Ordering},
-            Arc,
+            atomic::{AtomicBool, AtomicI64, AtomicU64, Ordering},
+            Arc, Mutex, RwLock,
         },
         thread::{self, Builder, JoinHandle},
         time::{Duration, Instant},
@@ -35,345 +49,5788 @@ pub const DEFAULT_MAX_LEDGER_SHREDS: u64 = 200_000_000;
 // Allow down to 50m, or 3.5 days at idle, 1hr at 50k load, around ~100GB
 pub const DEFAULT_MIN_MAX_LEDGER_SHREDS: u64 = 50_000_000;
 
+// The per-shred size assumed by DEFAULT_MAX_LEDGER_SHREDS above
+pub const DEFAULT_BYTES_PER_SHRED: u64 = 2_000;
+
+// Calibrating the shred size samples the last few full slots within this
+// many slots of the root
+const SHRED_SIZE_SAMPLE_WINDOW_SLOTS: u64 = 256;
+const SHRED_SIZE_SAMPLE_SLOTS: usize = 8;
+
+// The weight given to each new sample by CalibratedShredSizeEstimator
+pub const SHRED_SIZE_CALIBRATION_SMOOTHING: f64 = 0.2;
+
 // Perform blockstore cleanup at this interval to limit the overhead of cleanup
 // Cleanup will be considered after the latest root has advanced by this value
-const DEFAULT_CLEANUP_SLOT_INTERVAL: u64 = 512;
-// The above slot interval can be roughly equated to a time interval. So, scale
-// how often we check for cleanup with the interval. Doing so will avoid wasted
-// checks when we know that the latest root could not have advanced far enough
-//
-// Given that the timing of new slots/roots is not exact, divide by 10 to avoid
-// a long wait incase a check occurs just before the interval has elapsed
-const LOOP_LIMITER: Duration =
-    Duration::from_millis(DEFAULT_CLEANUP_SLOT_INTERVAL * DEFAULT_MS_PER_SLOT / 10);
+pub const DEFAULT_CLEANUP_SLOT_INTERVAL: u64 = 512;
 
-pub struct BlockstoreCleanupService {
-    t_cleanup: JoinHandle<()>,
-}
+// Never clean up this many slots below the root so that repair can still find
+// recent ancestors; this is well below what any shred limit retains in practice
+pub const DEFAULT_REPAIR_RETENTION_SLOTS: u64 = 1_024;
 
-impl BlockstoreCleanupService {
-    pub fn new(blockstore: Arc<Blockstore>, max_ledger_shreds: u64, exit: Arc<AtomicBool>) -> Self {
-        let mut last_purge_slot = 0;
-        let mut last_check_time = Instant::now();
+// With a shred count estimate, the blockstore is only scanned once the estimate
+// reaches this fraction of max_ledger_shreds
+pub const SHRED_ESTIMATE_SCAN_THRESHOLD: f64 = 0.9;
 
-        let t_cleanup = Builder::new()
-            .name("solBstoreClean".to_string())
-            .spawn(move || {
-                info!(
-                    "BlockstoreCleanupService has started with max ledger \
-                     shreds={max_ledger_shreds}",
-                );
-                loop {
-                    if exit.load(Ordering::Relaxed) {
-                        break;
-                    }
-                    if last_check_time.elapsed() > LOOP_LIMITER {
-                        Self::cleanup_ledger(
-                            &blockstore,
-                            max_ledger_shreds,
-                            &mut last_purge_slot,
-                            DEFAULT_CLEANUP_SLOT_INTERVAL,
-                        );
+// Report the service as idle once the root has not advanced for this long
+pub const DEFAULT_IDLE_THRESHOLD: Duration = Duration::from_secs(5 * 60);
 
-                        last_check_time = Instant::now();
-                    }
-                    // Only sleep for 1 second instead of LOOP_LIMITER so that this
-                    // thread can respond to the exit flag in a timely manner
-                    thread::sleep(Duration::from_secs(1));
-                }
-                info!("BlockstoreCleanupService has stopped");
-            })
-            .unwrap();
+// How long the service may go without completing a cleanup before it is
+// reported as stalled
+pub const DEFAULT_STALL_THRESHOLD: Duration = Duration::from_secs(10 * 60);
 
-        Self { t_cleanup }
-    }
+// The file in the ledger directory that holds the last purge slot, so that
+// the purge cadence carries over across restarts
+const LAST_PURGE_SLOT_FILE: &str = "blockstore_cleanup_last_purge_slot";
 
-    /// A helper function to `cleanup_ledger` which returns a tuple of the
-    /// following four elements suggesting whether to clean up the ledger:
-    ///
-    /// Return value (bool, Slot, u64):
-    /// - `slots_to_clean` (bool): a boolean value indicating whether there
-    ///   are any slots to clean.  If true, then `cleanup_ledger` function
-    ///   will then proceed with the ledger cleanup.
-    /// - `lowest_slot_to_purge` (Slot): the lowest slot to purge.  Any
-    ///   slot which is older or equal to `lowest_slot_to_purge` will be
-    ///   cleaned up.
-    /// - `total_shreds` (u64): the total estimated number of shreds before the
-    ///   `root`.
-    fn find_slots_to_clean(
-        blockstore: &Blockstore,
-        root: Slot,
-        max_ledger_shreds: u64,
-    ) -> (bool, Slot, u64) {
-        let data_shred_cf_name = DATA_SHRED_CF.to_string();
+// Back off from a failed purge for this long before the first retry
+pub const DEFAULT_PURGE_BASE_BACKOFF: Duration = Duration::from_millis(100);
 
-        let live_files = blockstore
-            .live_files_metadata()
-            .expect("Blockstore::live_files_metadata()");
-        let num_shreds = live_files
-            .iter()
-            .filter(|live_file| live_file.column_family_name == data_shred_cf_name)
-            .map(|file_meta| file_meta.num_entries)
-            .sum();
+// Retries of a failed purge back off for at most this long
+pub const DEFAULT_PURGE_MAX_BACKOFF: Duration = Duration::from_secs(1);
 
-        // Using the difference between the lowest and highest slot seen will
-        // result in overestimating the number of slots in the blockstore since
-        // there are likely to be some missing slots, such as when a leader is
-        // delinquent for their leader slots.
-        //
-        // With the below calculations, we will then end up underestimating the
-        // mean number of shreds per slot present in the blockstore which will
-        // result in cleaning more slots than necessary to get us
-        // below max_ledger_shreds.
-        //
-        // Given that the service runs on an interval, this is good because it
-        // means that we are building some headroom so the peak number of alive
-        // shreds doesn't get too large before the service's next run.
-        //
-        // Finally, we have a check to make sure that we don't purge any slots
-        // newer than the passed in root. This check is practically only
-        // relevant when a cluster has extended periods of not rooting slots.
-        // With healthy cluster operation, the minimum ledger size ensures
-        // that purged slots will be quite old in relation to the newest root.
-        let lowest_slot = blockstore.lowest_slot();
-        let highest_slot = blockstore
-            .highest_slot()
-            .expect("Blockstore::highest_slot()")
-            .unwrap_or(lowest_slot);
-        if highest_slot < lowest_slot {
-            error!(
-                "Skipping Blockstore cleanup: highest slot {highest_slot} < lowest slot \
-                 {lowest_slot}",
-            );
-            return (false, 0, num_shreds);
-        }
-        // The + 1 ensures we count the correct number of slots. Additionally,
-        // it guarantees num_slots >= 1 for the subsequent division.
-        let num_slots = highest_slot - lowest_slot + 1;
-        let mean_shreds_per_slot = num_shreds / num_slots;
-        info!(
-            "Blockstore has {num_shreds} alive shreds in slots [{lowest_slot}, {highest_slot}], \
-             mean of {mean_shreds_per_slot} shreds per slot",
-        );
+// How often join_timeout() checks whether the cleanup thread has stopped
+const JOIN_POLL_INTERVAL: Duration = Duration::from_millis(10);
 
-        if num_shreds <= max_ledger_shreds {
-            return (false, 0, num_shreds);
-        }
+// Once the shred limit is exceeded, clean this fraction below it
+pub const DEFAULT_HYSTERESIS: f64 = 0.1;
 
-        // Add an extra (mean_shreds_per_slot - 1) in the numerator
-        // so that our integer division rounds up
-        let num_slots_to_clean = (num_shreds - max_ledger_shreds + mean_shreds_per_slot - 1)
-            .checked_div(mean_shreds_per_slot);
+// Scanning the blockstore for cleanup is considered slow beyond this duration
+pub const DEFAULT_SLOW_SCAN_THRESHOLD: Duration = Duration::from_millis(500);
 
-        if let Some(num_slots_to_clean) = num_slots_to_clean {
-            // Ensure we don't cleanup anything past the last root we saw
-            let lowest_cleanup_slot = std::cmp::min(lowest_slot + num_slots_to_clean - 1, root);
-            (true, lowest_cleanup_slot, num_shreds)
-        } else {
-            error!("Skipping Blockstore cleanup: calculated mean of 0 shreds per slot");
-            (false, 0, num_shreds)
+/// The retention limit that determined how many slots were selected for
+/// cleaning.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RetentionLimit {
+    /// The number of live data shreds exceeded `max_ledger_shreds`.
+    Shreds,
+    /// The on-disk size of the blockstore exceeded `max_ledger_bytes`.
+    Bytes,
+    /// The blockstore contained slots older than `max_ledger_duration`.
+    Duration,
+    /// The free space on the ledger volume fell below
+    /// `min_free_disk_bytes`.
+    FreeDiskSpace,
+    /// The configured `cleanup_policy` selected the slots to clean.
+    Policy,
+}
+
+impl RetentionLimit {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Self::Shreds => "shreds",
+            Self::Bytes => "bytes",
+            Self::Duration => "duration",
+            Self::FreeDiskSpace => "free_disk_space",
+            Self::Policy => "policy",
         }
     }
+}
 
-    /// Checks for new roots and initiates a cleanup if the last cleanup was at
-    /// least `purge_interval` slots ago. A cleanup will no-op if the ledger
-    /// already has fewer than `max_ledger_shreds`; otherwise, the cleanup will
-    /// purge enough slots to get the ledger size below `max_ledger_shreds`.
-    ///
-    /// # Arguments
-    ///
-    /// - `max_ledger_shreds`: the number of shreds to keep since the new root.
-    /// - `last_purge_slot`: an both an input and output parameter indicating
-    ///   the id of the last purged slot.  As an input parameter, it works
-    ///   together with `purge_interval` on whether it is too early to perform
-    ///   ledger cleanup.  As an output parameter, it will be updated if this
-    ///   function actually performs the ledger cleanup.
-    /// - `purge_interval`: the minimum slot interval between two ledger
-    ///   cleanup.  When the max root fetched from the Blockstore minus
-    ///   `last_purge_slot` is fewer than `purge_interval`, the function will
-    ///   simply return `Ok` without actually running the ledger cleanup.
-    ///   In this case, `purge_interval` will remain unchanged.
-    ///
-    /// Also see `blockstore::purge_slot`.
-    pub fn cleanup_ledger(
-        blockstore: &Arc<Blockstore>,
-        max_ledger_shreds: u64,
-        last_purge_slot: &mut u64,
-        purge_interval: u64,
-    ) {
-        let root = blockstore.max_root();
-        if root - *last_purge_slot <= purge_interval {
-            return;
-        }
-        *last_purge_slot = root;
-        info!("Looking for Blockstore data to cleanup, latest root: {root}");
+/// Estimates the average on-disk size of a data shred, allowing byte-based
+/// retention to be derived from the shred count.
+pub trait ShredSizeEstimator {
+    fn avg_bytes_per_shred(&self, blockstore: &Blockstore) -> u64;
 
-        let disk_utilization_pre = blockstore.storage_size();
-        let (slots_to_clean, lowest_cleanup_slot, total_shreds) =
-            Self::find_slots_to_clean(blockstore, root, max_ledger_shreds);
+    /// Called with the mean on-disk size of a data shred sampled from the
+    /// blockstore every `BlockstoreCleanupConfig::shred_size_calibration_interval`
+    /// checks. Estimators that do not adapt ignore it.
+    fn calibrate(&self, _sampled_bytes_per_shred: u64) {}
+}
 
-        if slots_to_clean {
-            *blockstore.lowest_cleanup_slot.write().unwrap() = lowest_cleanup_slot;
+/// A `ShredSizeEstimator` that always returns the same size.
+#[derive(Clone, Copy, Debug)]
+pub struct ConstantShredSizeEstimator(pub u64);
 
-            let mut purge_time = Measure::start("purge_slots()");
-            // purge any slots older than lowest_cleanup_slot.
-            blockstore.purge_slots(0, lowest_cleanup_slot, PurgeType::CompactionFilter);
-            // Update only after purge operation.
-            // Safety: This value can be used by compaction_filters shared via Arc<AtomicU64>.
-            // Compactions are async and run as a multi-threaded background job. However, this
-            // shouldn't cause consistency issues for iterators and getters because we have
-            // already expired all affected keys (older than or equal to lowest_cleanup_slot)
-            // by the above `purge_slots`. According to the general RocksDB design where SST
-            // files are immutable, even running iterators aren't affected; the database grabs
-            // a snapshot of the live set of sst files at iterator's creation.
-            // Also, we passed the PurgeType::CompactionFilter, meaning no delete_range for
-            // transaction_status and address_signatures CFs. These are fine because they
-            // don't require strong consistent view for their operation.
-            blockstore.set_max_expired_slot(lowest_cleanup_slot);
-            purge_time.stop();
-            info!("Cleaned up Blockstore data older than slot {lowest_cleanup_slot}. {purge_time}");
-        }
+impl Default for ConstantShredSizeEstimator {
+    fn default() -> Self {
+        Self(DEFAULT_BYTES_PER_SHRED)
+    }
+}
 
-        let disk_utilization_post = blockstore.storage_size();
-        Self::report_disk_metrics(disk_utilization_pre, disk_utilization_post, total_shreds);
+impl ShredSizeEstimator for ConstantShredSizeEstimator {
+    fn avg_bytes_per_shred(&self, _blockstore: &Blockstore) -> u64 {
+        self.0
     }
+}
 
-    fn report_disk_metrics(
-        pre: BlockstoreResult<u64>,
-        post: BlockstoreResult<u64>,
-        total_shreds: u64,
-    ) {
-        if let (Ok(pre), Ok(post)) = (pre, post) {
-            datapoint_info!(
-                "ledger_disk_utilization",
-                ("disk_utilization_pre", pre as i64, i64),
-                ("disk_utilization_post", post as i64, i64),
-                ("disk_utilization_delta", (pre as i64 - post as i64), i64),
-                ("total_shreds", total_shreds, i64),
-            );
+/// A `ShredSizeEstimator` that starts from an assumed size and moves towards
+/// the sizes sampled from the blockstore, smoothed with an exponential moving
+/// average so that a few unusual slots do not swing byte-based retention.
+#[derive(Debug)]
+pub struct CalibratedShredSizeEstimator {
+    bytes_per_shred: AtomicU64,
+}
+
+impl CalibratedShredSizeEstimator {
+    pub fn new(bytes_per_shred: u64) -> Self {
+        Self {
+            bytes_per_shred: AtomicU64::new(bytes_per_shred),
         }
     }
 
-    pub fn join(self) -> thread::Result<()> {
-        self.t_cleanup.join()
+    pub fn bytes_per_shred(&self) -> u64 {
+        self.bytes_per_shred.load(Ordering::Relaxed)
     }
 }
-#[cfg(test)]
-mod tests {
-    use {
-        super::*,
-        crate::{blockstore::make_many_slot_entries, get_tmp_ledger_path_auto_delete},
-    };
 
-    fn flush_blockstore_contents_to_disk(blockstore: Blockstore) -> Blockstore {
-        // The find_slots_to_clean() routine uses a method that queries data
-        // from RocksDB SST files. On a running validator, these are created
-        // fairly regularly as new data is coming in and contents of memory are
-        // pushed to disk. In a unit test environment, we aren't pushing nearly
-        // enough data for this to happen organically. So, instead open and
-        // close the Blockstore which will perform the flush to SSTs.
-        let ledger_path = blockstore.ledger_path().clone();
-        drop(blockstore);
-        Blockstore::open(&ledger_path).unwrap()
+impl Default for CalibratedShredSizeEstimator {
+    fn default() -> Self {
+        Self::new(DEFAULT_BYTES_PER_SHRED)
     }
+}
 
-    #[test]
-    fn test_find_slots_to_clean() {
-        // BlockstoreCleanupService::find_slots_to_clean() does not modify the
-        // Blockstore, so we can make repeated calls on the same slots
-        solana_logger::setup();
-        let ledger_path = get_tmp_ledger_path_auto_delete!();
-        let blockstore = Blockstore::open(ledger_path.path()).unwrap();
+impl ShredSizeEstimator for CalibratedShredSizeEstimator {
+    fn avg_bytes_per_shred(&self, _blockstore: &Blockstore) -> u64 {
+        self.bytes_per_shred()
+    }
 
-        // Construct and build some shreds for slots [1, 10]
-        let num_slots: u64 = 10;
-        let num_entries = 200;
-        let (shreds, _) = make_many_slot_entries(1, num_slots, num_entries);
-        let shreds_per_slot = (shreds.len() / num_slots as usize) as u64;
-        assert!(shreds_per_slot > 1);
-        blockstore.insert_shreds(shreds, None, false).unwrap();
+    fn calibrate(&self, sampled_bytes_per_shred: u64) {
+        // Only the cleanup thread calibrates, so a plain load and store is enough
+        let bytes_per_shred = self.bytes_per_shred() as f64;
+        let bytes_per_shred = bytes_per_shred
+            + SHRED_SIZE_CALIBRATION_SMOOTHING * (sampled_bytes_per_shred as f64 - bytes_per_shred);
+        self.bytes_per_shred
+            .store(bytes_per_shred.round() as u64, Ordering::Relaxed);
+    }
+}
 
-        // Initiate a flush so inserted shreds found by find_slots_to_clean()
-        let blockstore = Arc::new(flush_blockstore_contents_to_disk(blockstore));
+/// Cheaply estimates the number of live data shreds in the blockstore, such
+/// as from a running count of inserted shreds, so that cleanups can skip
+/// scanning the blockstore while it is well below `max_ledger_shreds`.
+pub trait ShredCountEstimator {
+    /// Returns the estimated number of live data shreds, or `None` if no
+    /// estimate is available and the blockstore must be scanned.
+    fn estimated_num_shreds(&self, blockstore: &Blockstore) -> Option<u64>;
+}
 
-        // Ensure no cleaning of slots > last_root
-        let last_root = 0;
-        let max_ledger_shreds = 0;
-        let (should_clean, lowest_purged, _) = BlockstoreCleanupService::find_slots_to_clean(
-            &blockstore,
-            last_root,
-            max_ledger_shreds,
-        );
-        // Slot 0 will exist in blockstore with zero shreds since it is slot
-        // 1's parent. Thus, slot 0 will be identified for clean.
-        assert!(should_clean && lowest_purged == 0);
-        // Now, set max_ledger_shreds to 1, slot 0 still eligible for clean
-        let max_ledger_shreds = 1;
-        let (should_clean, lowest_purged, _) = BlockstoreCleanupService::find_slots_to_clean(
-            &blockstore,
-            last_root,
-            max_ledger_shreds,
-        );
-        assert!(should_clean && lowest_purged == 0);
+/// Returns the number of bytes available to unprivileged users on the
+/// filesystem containing `path`.
+#[cfg(unix)]
+#[allow(clippy::useless_conversion)]
+pub fn available_disk_space(path: &Path) -> io::Result<u64> {
+    let stat = statvfs(path)?;
+    // The field types vary by platform
+    Ok(u64::from(stat.f_bavail).saturating_mul(u64::from(stat.f_frsize)))
+}
 
-        // Ensure no cleaning if blockstore contains fewer than max_ledger_shreds
-        let last_root = num_slots;
-        let max_ledger_shreds = (shreds_per_slot * num_slots) + 1;
-        let (should_clean, lowest_purged, _) = BlockstoreCleanupService::find_slots_to_clean(
-            &blockstore,
-            last_root,
-            max_ledger_shreds,
-        );
-        assert!(!should_clean && lowest_purged == 0);
+/// Returns the total size in bytes of the filesystem containing `path`.
+#[cfg(unix)]
+#[allow(clippy::useless_conversion)]
+pub fn disk_capacity(path: &Path) -> io::Result<u64> {
+    let stat = statvfs(path)?;
+    // The field types vary by platform
+    Ok(u64::from(stat.f_blocks).saturating_mul(u64::from(stat.f_frsize)))
+}
 
-        for slot in 1..=num_slots {
-            // Set last_root to make slots <= slot eligible for cleaning
-            let last_root = slot;
-            // Set max_ledger_shreds to 0 so that all eligible slots are cleaned
-            let max_ledger_shreds = 0;
-            let (should_clean, lowest_purged, _) = BlockstoreCleanupService::find_slots_to_clean(
-                &blockstore,
-                last_root,
-                max_ledger_shreds,
-            );
-            assert!(should_clean && lowest_purged == slot);
+#[cfg(unix)]
+fn statvfs(path: &Path) -> io::Result<libc::statvfs> {
+    use std::{ffi::CString, mem::MaybeUninit, os::unix::ffi::OsStrExt};
 
-            // Set last_root to make all slots eligible for cleaning
-            let last_root = num_slots + 1;
-            // Set max_ledger_shreds to the number of shreds in slots > slot.
-            // This will make it so that slots [1, slot] are cleaned
-            let max_ledger_shreds = shreds_per_slot * (num_slots - slot);
-            let (should_clean, lowest_purged, _) = BlockstoreCleanupService::find_slots_to_clean(
-                &blockstore,
-                last_root,
-                max_ledger_shreds,
-            );
-            assert!(should_clean && lowest_purged == slot);
+    let path = CString::new(path.as_os_str().as_bytes())
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?;
+    let mut stat = MaybeUninit::<libc::statvfs>::uninit();
+    if unsafe { libc::statvfs(path.as_ptr(), stat.as_mut_ptr()) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(unsafe { stat.assume_init() })
+}
+
+#[cfg(not(unix))]
+pub fn available_disk_space(_path: &Path) -> io::Result<u64> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "querying free disk space is not supported on this platform",
+    ))
+}
+
+#[cfg(not(unix))]
+pub fn disk_capacity(_path: &Path) -> io::Result<u64> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "querying disk capacity is not supported on this platform",
+    ))
+}
+
+/// Decides how much of the blockstore to clean, in place of the retention
+/// limits in `BlockstoreCleanupConfig`.
+pub trait CleanupPolicy {
+    /// Returns whether any slots should be cleaned, the highest slot to clean
+    /// and the estimated number of live data shreds in the blockstore.
+    ///
+    /// The returned slot is still subject to `min_slots_to_retain` and
+    /// `protected_slot_floor`. Only slots below the root may be cleaned; a
+    /// slot at or above the root is a bug in the policy: nothing is cleaned
+    /// and the violation is reported.
+    fn slots_to_clean(&self, blockstore: &Blockstore, root: Slot) -> (bool, Slot, u64);
+}
+
+/// A `CleanupPolicy` that retains `max_ledger_shreds` live data shreds, as the
+/// service does by default.
+#[derive(Clone)]
+pub struct ShredCountPolicy {
+    config: BlockstoreCleanupConfig,
+}
+
+impl ShredCountPolicy {
+    /// Applies the shred limit of `config`, along with the settings that
+    /// shape it, such as `hysteresis` and `shred_count_estimator`. The other
+    /// retention limits of `config` and its own `cleanup_policy` are
+    /// ignored.
+    pub fn new(config: &BlockstoreCleanupConfig) -> Self {
+        Self {
+            config: BlockstoreCleanupConfig {
+                max_ledger_bytes: None,
+                max_ledger_duration: None,
+                min_free_disk_bytes: None,
+                cleanup_policy: None,
+                ..config.clone()
+            },
         }
     }
+}
 
-    #[test]
-    fn test_cleanup() {
-        solana_logger::setup();
-        let ledger_path = get_tmp_ledger_path_auto_delete!();
-        let blockstore = Blockstore::open(ledger_path.path()).unwrap();
-        let (shreds, _) = make_many_slot_entries(0, 50, 5);
-        blockstore.insert_shreds(shreds, None, false).unwrap();
+impl CleanupPolicy for ShredCountPolicy {
+    fn slots_to_clean(&self, blockstore: &Blockstore, root: Slot) -> (bool, Slot, u64) {
+        let slots_to_clean =
+            BlockstoreCleanupService::find_slots_to_clean(blockstore, root, &self.config);
+        (
+            slots_to_clean.should_clean,
+            slots_to_clean.lowest_cleanup_slot,
+            slots_to_clean.total_shreds,
+        )
+    }
+}
 
-        // Initiate a flush so inserted shreds found by find_slots_to_clean()
-        let blockstore = Arc::new(flush_blockstore_contents_to_disk(blockstore));
+/// The slots selected for cleaning by
+/// `BlockstoreCleanupService::find_slots_to_clean`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SlotsToClean {
+    /// Whether there are any slots to clean.
+    pub should_clean: bool,
+    /// Every slot older than or equal to this slot is to be cleaned; only
+    /// meaningful if `should_clean` is set.
+    pub lowest_cleanup_slot: Slot,
+    /// The estimated number of live data shreds in the blockstore.
+    pub total_shreds: u64,
+    /// The number of slots spanned by the blockstore, from its lowest to its
+    /// highest slot; zero if the blockstore was not scanned.
+    pub total_slots: u64,
+    /// The retention limit that determined the number of slots to clean, if
+    /// any.
+    pub limit: Option<RetentionLimit>,
+    /// Whether the blockstore is expected to remain above
+    /// `max_ledger_shreds` after the cleanup, because the slots protected by
+    /// the config limited it.
+    pub over_budget: bool,
+    /// Whether the blockstore was scanned, even if it turned out to be
+    /// empty. Otherwise, `total_shreds` is at most an estimate.
+    pub scanned: bool,
+}
 
-        // Mark 50 as a root to kill all but 5 shreds, which will be in the newest slots
-        let mut last_purge_slot = 0;
-        blockstore.set_roots([50].iter()).unwrap();
-        BlockstoreCleanupService::cleanup_ledger(&blockstore, 5, &mut last_purge_slot, 10);
-        assert_eq!(last_purge_slot, 50);
+impl SlotsToClean {
+    fn nothing(total_shreds: u64, total_slots: u64) -> Self {
+        Self {
+            should_clean: false,
+            lowest_cleanup_slot: 0,
+            total_shreds,
+            total_slots,
+            limit: None,
+            over_budget: false,
+            scanned: true,
+        }
+    }
 
-        //check that 0-40 don't exist
-        blockstore
-            .slot_meta_iterator(0)
-            .unwrap()
-            .for_each(|(slot, _)| assert!(slot > 40));
+    fn not_scanned(total_shreds: u64) -> Self {
+        Self {
+            scanned: false,
+            ..Self::nothing(total_shreds, 0)
+        }
+    }
+}
+
+/// The overall state of a `BlockstoreCleanupService`, in order of
+/// precedence from `Paused` down.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CleanupHealth {
+    /// Cleanups have completed recently and kept the blockstore within
+    /// `max_ledger_shreds`.
+    Healthy,
+    /// The last cleanup was limited by the protected slots and left the
+    /// blockstore above `max_ledger_shreds`.
+    OverBudget,
+    /// No cleanup has scanned or purged the blockstore within the configured
+    /// `stall_threshold`.
+    Stalled,
+    /// The service has been paused.
+    Paused,
+}
+
+/// Preserves slots elsewhere before they are purged from the blockstore.
+pub trait SlotArchiver {
+    /// Archives \[`from_slot`, `to_slot`\], returning the number of bytes
+    /// archived. If an error is returned, the slots are not purged and will
+    /// be archived again on a later cleanup.
+    fn archive(
+        &self,
+        blockstore: &Blockstore,
+        from_slot: Slot,
+        to_slot: Slot,
+    ) -> BlockstoreResult<u64>;
+}
+
+/// The source of time for the service's polling loop, which lets tests
+/// control when a cleanup is due without waiting on the wall clock.
+pub trait Clock {
+    fn now(&self) -> Instant;
+    fn sleep(&self, duration: Duration);
+}
+
+/// A `Clock` backed by `Instant::now()` and `thread::sleep()`.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn sleep(&self, duration: Duration) {
+        thread::sleep(duration)
+    }
+}
+
+/// A `SlotArchiver` that copies the data shreds and roots of the slots to be
+/// purged into a secondary blockstore. The secondary blockstore can then run
+/// its own `BlockstoreCleanupService` with larger limits.
+///
+/// A failed copy normally defers the purge of the primary blockstore, but
+/// only for `max_consecutive_failures` cleanups in a row; after that, the
+/// slots are purged without having been archived so that the primary
+/// blockstore does not grow without bound.
+pub struct BlockstoreArchiver {
+    secondary: Arc<Blockstore>,
+    max_consecutive_failures: u64,
+    consecutive_failures: AtomicU64,
+}
+
+impl BlockstoreArchiver {
+    pub fn new(secondary: Arc<Blockstore>, max_consecutive_failures: u64) -> Self {
+        Self {
+            secondary,
+            max_consecutive_failures,
+            consecutive_failures: AtomicU64::default(),
+        }
+    }
+
+    fn copy_slots(
+        &self,
+        blockstore: &Blockstore,
+        from_slot: Slot,
+        to_slot: Slot,
+    ) -> BlockstoreResult<u64> {
+        let mut num_bytes = 0;
+        let mut roots = vec![];
+        for slot in from_slot..=to_slot {
+            let shreds = blockstore.get_data_shreds_for_slot(slot, 0)?;
+            if shreds.is_empty() {
+                continue;
+            }
+            num_bytes += shreds
+                .iter()
+                .map(|shred| shred.payload().len() as u64)
+                .sum::<u64>();
+            self.secondary.insert_shreds(shreds, None, false)?;
+            if blockstore.is_root(slot) {
+                roots.push(slot);
+            }
+        }
+        self.secondary.set_roots(roots.iter())?;
+        Ok(num_bytes)
+    }
+}
+
+impl SlotArchiver for BlockstoreArchiver {
+    fn archive(
+        &self,
+        blockstore: &Blockstore,
+        from_slot: Slot,
+        to_slot: Slot,
+    ) -> BlockstoreResult<u64> {
+        match self.copy_slots(blockstore, from_slot, to_slot) {
+            Ok(num_bytes) => {
+                self.consecutive_failures.store(0, Ordering::Relaxed);
+                Ok(num_bytes)
+            }
+            Err(err) => {
+                let failures = self.consecutive_failures.fetch_add(1, Ordering::Relaxed) + 1;
+                if failures <= self.max_consecutive_failures {
+                    return Err(err);
+                }
+                error!(
+                    "Purging slots [{from_slot}, {to_slot}] without archiving them after \
+                     {failures} consecutive failures: {err:?}",
+                );
+                self.consecutive_failures.store(0, Ordering::Relaxed);
+                Ok(0)
+            }
+        }
+    }
+}
+
+/// Invoked with the inclusive range of slots removed by a purge.
+pub type PurgeCallback = Arc<dyn Fn(Slot, Slot) + Send + Sync>;
+
+/// Invoked with the proposed `lowest_cleanup_slot` before a purge; returning
+/// false defers the purge.
+pub type PurgeVeto = Arc<dyn Fn(Slot) -> bool + Send + Sync>;
+
+/// Invoked at the start of each cleanup to supply the `max_ledger_shreds`
+/// for that cleanup.
+pub type MaxLedgerShredsProvider = Arc<dyn Fn() -> u64 + Send + Sync>;
+
+/// Sent to `BlockstoreCleanupConfig::purge_event_sender` after each
+/// successful purge.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PurgeEvent {
+    /// The lowest slot in the blockstore before the purge.
+    pub from: Slot,
+    /// The highest slot that was purged.
+    pub to: Slot,
+    /// The estimated number of live data shreds that were purged.
+    pub shreds: u64,
+    /// When the purge completed.
+    pub at: Instant,
+}
+
+/// Retention settings for `BlockstoreCleanupService`.
+///
+/// Every field has a default, so callers set only the ones they need and
+/// take the rest from `BlockstoreCleanupConfig::default()` with struct
+/// update syntax, then hand the result to
+/// `BlockstoreCleanupService::new_with_config()`. `new()` covers the common
+/// case of only limiting the number of shreds.
+#[derive(Clone)]
+pub struct BlockstoreCleanupConfig {
+    /// The number of live data shreds to retain in the blockstore.
+    pub max_ledger_shreds: u64,
+    /// When set, nothing is ever cleaned, whatever the retention limits, and
+    /// the blockstore is never scanned. The service keeps polling the root
+    /// and reporting disk metrics, so archival nodes can run it for its
+    /// metrics alone.
+    pub retain_all: bool,
+    /// Once `max_ledger_shreds` is exceeded, clean down to this fraction
+    /// below it rather than to exactly `max_ledger_shreds`. The resulting
+    /// purges are larger but less frequent, which avoids purging (and
+    /// compacting) a handful of slots every interval when the blockstore
+    /// hovers around the limit. Values outside of \[0.0, 0.5\] are clamped.
+    /// Defaults to `DEFAULT_HYSTERESIS`.
+    pub hysteresis: f64,
+    /// When set, a warning is logged and reported whenever the number of
+    /// live data shreds exceeds this threshold but not yet
+    /// `max_ledger_shreds`. Nothing is purged until `max_ledger_shreds` is
+    /// exceeded, so this gives operators lead time to grow the ledger volume
+    /// before history starts to be dropped.
+    pub warn_threshold_shreds: Option<u64>,
+    /// The number of bytes the blockstore may occupy on disk. When set, the
+    /// actual storage size is used in addition to the shred count; whichever
+    /// limit requires cleaning more slots is the one that takes effect.
+    pub max_ledger_bytes: Option<u64>,
+    /// When set, `max_ledger_bytes` is compared against the number of live
+    /// data shreds multiplied by the estimated shred size rather than
+    /// against the blockstore's storage size.
+    pub shred_size_estimator: Option<Arc<dyn ShredSizeEstimator + Send + Sync>>,
+    /// When set and `max_ledger_shreds` is the only retention limit, the
+    /// blockstore is only scanned once the estimated number of live data
+    /// shreds is within `SHRED_ESTIMATE_SCAN_THRESHOLD` of
+    /// `max_ledger_shreds`. Below that, the cleanup is skipped without
+    /// paying for the scan.
+    pub shred_count_estimator: Option<Arc<dyn ShredCountEstimator + Send + Sync>>,
+    /// The amount of history to retain below the latest root. The duration is
+    /// translated into a window of slots with `ms_per_slot`, so the retained
+    /// window does not depend on how quickly slots are actually arriving
+    /// (such as when a node is catching up).
+    pub max_ledger_duration: Option<Duration>,
+    /// The amount of free space to maintain on the volume holding the
+    /// ledger. When less is available, enough of the oldest slots are
+    /// cleaned to make up the difference, estimated from the mean on-disk
+    /// size of a slot. This suits volumes shared with other data, where the
+    /// size of the blockstore itself is not the real constraint. Purged
+    /// space is only returned to the filesystem once it has been compacted;
+    /// see `compact_after_purge`.
+    pub min_free_disk_bytes: Option<u64>,
+    /// The capacity of the ledger volume, against which the blockstore's
+    /// size is reported as a percentage in the `ledger_disk_utilization`
+    /// datapoint. If unset, the size of the filesystem containing the ledger
+    /// is used.
+    pub ledger_volume_capacity_bytes: Option<u64>,
+    /// When set, the policy decides which slots to clean and the retention
+    /// limits above are ignored.
+    pub cleanup_policy: Option<Arc<dyn CleanupPolicy + Send + Sync>>,
+    /// The expected duration of a slot, used to translate
+    /// `max_ledger_duration` into a number of slots.
+    pub ms_per_slot: u64,
+    /// The number of slots the root must advance by between cleanups.
+    /// Every cleanup competes with other Blockstore users for RocksDB
+    /// resources; values below ~64 risk starving them.
+    pub purge_interval: u64,
+    /// When set, `purge_interval` is adjusted after each cleanup based on
+    /// how quickly the blockstore is growing: shortened when the growth
+    /// would reach `max_ledger_shreds` soon, and lengthened when the node is
+    /// idle. `purge_interval` is then only the starting point.
+    pub adaptive_purge_interval: Option<AdaptivePurgeInterval>,
+    /// The minimum time between cleanups, in addition to `purge_interval`;
+    /// both must have elapsed for a cleanup to run. This keeps a burst of
+    /// roots, such as when the node catches up, from triggering back to
+    /// back cleanups.
+    pub min_wall_interval: Duration,
+    /// The minimum time between scans of the blockstore, which compete with
+    /// other readers for RocksDB resources even when nothing is purged.
+    /// Within the interval, a cleanup only runs if `shred_count_estimator`
+    /// estimates that `max_ledger_shreds` has been exceeded; without an
+    /// estimator, it waits for the interval to elapse.
+    pub min_scan_interval: Duration,
+    /// The number of slots below the root that are never cleaned up,
+    /// regardless of the retention limits above. This protects the slots
+    /// that replay and RPC consumers may still need.
+    pub min_slots_to_retain: u64,
+    /// The number of slots below the root that repair may still need to
+    /// serve or look up the ancestors of; these are never cleaned up, no
+    /// matter how small the other limits are. This is meant to be sized to
+    /// the repair window, whereas `min_slots_to_retain` is a general
+    /// retention floor; the larger of the two takes effect. Defaults to
+    /// `DEFAULT_REPAIR_RETENTION_SLOTS`.
+    pub repair_retention_slots: u64,
+    /// The lowest slot that must not be purged, such as the base slot of a
+    /// snapshot that is being generated. The value is shared so that the
+    /// snapshot machinery can move it; if the retention limits call for
+    /// purging at or beyond it, the purge is limited to the slots below it.
+    pub protected_slot_floor: Option<Arc<AtomicU64>>,
+    /// The number of slots to subtract from the root before deciding what
+    /// to clean, so that cleanups stay well below a root that replay may
+    /// not have durably committed yet. Larger values protect more slots
+    /// that a late fork switch might need, at the cost of retaining that
+    /// many more slots than the limits call for; the window-based limits,
+    /// such as `min_slots_to_retain`, are then relative to the lagged root.
+    pub confirmation_lag: u64,
+    /// Slots that must survive cleanup regardless of the retention limits,
+    /// such as slots kept around to debug a consensus issue. Cleanups are
+    /// limited to the slots below the lowest pinned slot, so pinning an old
+    /// slot effectively disables purging until it is unpinned.
+    pub pinned_slots: Arc<RwLock<HashSet<Slot>>>,
+    /// The percentage, from 0 to 100, of the slots handed to the current
+    /// purge that have been purged so far. It is updated after every step
+    /// set up by `max_slots_per_purge` and stays at 100 once a purge
+    /// completes, so a long catch-up purge can be seen to advance; see also
+    /// `BlockstoreCleanupService::purge_progress()`.
+    pub purge_progress: Arc<AtomicU64>,
+    /// The lowest slot with a `SlotMeta` in the blockstore, which is not 0
+    /// for a node that booted from a snapshot. The cleanup thread detects
+    /// it at startup and after every purge, and cleanups scan and purge
+    /// from there instead of from slot 0; see also
+    /// `BlockstoreCleanupService::lowest_existing_slot()`.
+    pub lowest_existing_slot: Arc<AtomicU64>,
+    /// When set, cleanups end at an epoch boundary so that every retained
+    /// epoch is complete. This retains up to an epoch's worth of slots more
+    /// than the limits above call for, but tooling that works on whole
+    /// epochs never encounters a partial one.
+    pub epoch_schedule: Option<EpochSchedule>,
+    /// Called on the cleanup thread after each successful purge, once
+    /// `lowest_cleanup_slot` has been released and the max expired slot has
+    /// been updated. The callback runs before the next cleanup can start, so
+    /// it should be cheap.
+    pub on_purge: Option<PurgeCallback>,
+    /// Receives a `PurgeEvent` after each successful purge, alongside
+    /// `on_purge`. The channel should be bounded; events are sent without
+    /// blocking and dropped if it is full, so that a slow subscriber cannot
+    /// stall the cleanup.
+    pub purge_event_sender: Option<Sender<PurgeEvent>>,
+    /// Consulted on the cleanup thread before each purge, such as to hold
+    /// off purging while an external backup is running. If it returns
+    /// false, nothing is purged and the cleanup is retried on the next check
+    /// rather than once the root has advanced by another `purge_interval`.
+    /// Unlike `BlockstoreCleanupService::pause()`, it can consult live
+    /// state at the time of each purge.
+    pub purge_veto: Option<PurgeVeto>,
+    /// Set by snapshot generation while it reads slots that a cleanup could
+    /// purge. Like a `purge_veto`, a set flag defers the purge to the next
+    /// check. The cleanup thread only loads the flag, with `Acquire`
+    /// ordering, right before it releases the slots through
+    /// `lowest_cleanup_slot`; it never waits on it and holds no locks while
+    /// loading it, so it cannot deadlock with the snapshot code. A purge that
+    /// is already underway when the flag is set runs to completion, so the
+    /// snapshot code should set the flag, with `Release` ordering, before
+    /// checking `Blockstore::lowest_cleanup_slot()` for the slots it needs.
+    pub snapshot_in_progress: Option<Arc<AtomicBool>>,
+    /// Consulted on the cleanup thread at the start of each cleanup for the
+    /// `max_ledger_shreds` to clean down to, overriding the configured value
+    /// and any set with `BlockstoreCleanupService::set_max_ledger_shreds()`.
+    /// This lets operators derive the budget from live conditions, such as
+    /// free disk space.
+    pub max_ledger_shreds_provider: Option<MaxLedgerShredsProvider>,
+    /// Called with the slots that are about to be purged; the purge is
+    /// skipped until a later cleanup if archiving fails.
+    pub archiver: Option<Arc<dyn SlotArchiver + Send + Sync>>,
+    /// Determine and report what would be purged without modifying the
+    /// Blockstore.
+    pub dry_run: bool,
+    /// How slots are purged:
+    /// - `PurgeType::CompactionFilter` (default) range-deletes the slot keyed
+    ///   columns and leaves the transaction status columns to the compaction
+    ///   filter. This is cheap, but disk space is only reclaimed as RocksDB
+    ///   compacts in the background.
+    /// - `PurgeType::Exact` additionally deletes the transaction status
+    ///   entries for the purged slots, which costs more CPU and I/O during
+    ///   the purge but does not depend on the compaction filter keeping up.
+    pub purge_type: PurgeType,
+    /// When set, the transaction metadata columns (`TransactionStatus`,
+    /// `AddressSignatures`, `TransactionMemos`) are kept for this many slots
+    /// below the slots whose shreds are purged, rather than expiring with
+    /// them. Shreds are the bulk of the blockstore while this metadata is
+    /// comparatively small, so an RPC node can hold a longer metadata window
+    /// on the same disk. The shred columns are range-deleted as usual, while
+    /// the metadata is only dropped by the compaction filter once it falls
+    /// below the metadata cutoff, so `purge_type` is treated as
+    /// `PurgeType::CompactionFilter`: `PurgeType::Exact` finds the metadata
+    /// to delete through the shreds, which are gone by then.
+    ///
+    /// Blockstore reads are bounded by `lowest_cleanup_slot`, so the extra
+    /// metadata is not served by `get_transaction_status()` and friends;
+    /// it is meant for tools that read the columns directly, such as when
+    /// uploading transaction history to long-term storage.
+    pub metadata_retention_slots: Option<u64>,
+    /// Compact the shred columns over the purged slots right after each
+    /// purge step. This costs extra I/O on the cleanup thread, but the space
+    /// held by the purged shreds is reclaimed immediately rather than
+    /// whenever RocksDB compacts those files, so the disk utilization delta
+    /// reported for the cleanup reflects what the purge actually freed.
+    pub compact_after_purge: bool,
+    /// The columns compacted when `compact_after_purge` is set. Only the
+    /// key range of the purged slots is compacted, so adding columns that
+    /// hold little data costs little, but the shred columns are where most
+    /// of the space is freed.
+    pub compaction_columns: Vec<CompactionColumn>,
+    /// The maximum number of slots to purge with a single `purge_slots`
+    /// call. A large backlog is then purged in several steps, oldest first,
+    /// which bounds the amount of work handed to RocksDB at once and lets
+    /// the service stop between steps when exiting. Combined with
+    /// `compact_after_purge`, each step is compacted as soon as it is
+    /// purged, so disk space is freed progressively rather than only once
+    /// the whole backlog is gone.
+    pub max_slots_per_purge: Option<u64>,
+    /// The rate at which purge steps may remove data, estimated from the
+    /// mean on-disk size of a slot. The service waits between the steps
+    /// set up by `max_slots_per_purge` as needed to stay under it. This
+    /// trades slower disk reclamation for less competition with replay for
+    /// RocksDB I/O; a large backlog simply takes longer to purge.
+    pub max_purge_bytes_per_sec: Option<u64>,
+    /// How long the service sleeps between checks of the exit flag and
+    /// whether a cleanup is due.
+    pub exit_poll_interval: Duration,
+    /// With `PurgeType::CompactionFilter`, RocksDB reclaims the space of
+    /// purged slots in the background, so the disk utilization sampled right
+    /// after a purge understates what it freed. When set, the service waits
+    /// up to this long after a purge, or until the storage size stops
+    /// changing between checks of the exit flag, before sampling it again.
+    /// This holds up the cleanup thread, so it is off by default.
+    pub metrics_settle_delay: Option<Duration>,
+    /// A cleanup that fails, such as on a transient RocksDB error, is
+    /// retried after this long rather than only on the next check. The wait
+    /// doubles with each retry; a zero value disables retries.
+    pub purge_base_backoff: Duration,
+    /// The cleanup gives up on retrying once the wait before the next retry
+    /// would exceed this, and leaves it to the next check. This bounds how
+    /// long a struggling disk holds up the cleanup thread.
+    pub purge_max_backoff: Duration,
+    /// The prefix of the name of the cleanup thread, which is named
+    /// `{thread_name_prefix}Clean`. Linux truncates thread names to 15
+    /// bytes, so the prefix should be at most 10.
+    pub thread_name_prefix: String,
+    /// Added to the nice value of the cleanup thread, which runs all purges
+    /// and compactions; positive values keep their I/O from contending with
+    /// replay. Only supported on Linux; failing to apply it is logged and
+    /// otherwise ignored.
+    pub thread_niceness_adj: i8,
+    /// Run a cleanup against the blockstore's current root as soon as the
+    /// service starts, before waiting for the root to advance. A node that
+    /// restarts with a blockstore that is already over its limits is then
+    /// brought back under them without delay. The cleanup runs on the
+    /// service's thread, so it does not hold up the caller.
+    pub purge_on_start: bool,
+    /// Save the last purge slot to a `blockstore_cleanup_last_purge_slot`
+    /// file in the ledger directory, and resume from it on the next start,
+    /// so that a restart does not reset the purge cadence. The file lives
+    /// next to, not in, the RocksDB files: `solana-ledger-tool blockstore
+    /// copy` only copies shreds, so its target starts from a last purge slot
+    /// of 0, whereas a copy or filesystem snapshot of the whole ledger
+    /// directory carries the file over and resumes from the original's slot.
+    /// A saved slot above the root of the blockstore it is loaded into is
+    /// ignored.
+    pub persist_last_purge_slot: bool,
+    /// A `ledger_cleanup_idle` datapoint is emitted on every check once the
+    /// root has not advanced for this long. Unlike a blockstore that is
+    /// simply under its limits, this indicates that the node is not rooting
+    /// slots.
+    pub idle_threshold: Duration,
+    /// `BlockstoreCleanupService::health()` reports the service as stalled
+    /// once it has gone this long without completing a cleanup, whether
+    /// because a purge is stuck or because purges keep failing.
+    pub stall_threshold: Duration,
+    /// When set, a `ledger_cleanup_shred_histogram` datapoint with the
+    /// distribution of the number of shreds per slot is emitted on every
+    /// Nth check for a cleanup. Producing it walks the slot metas, so it
+    /// should be sampled sparingly; it is meant to help choose
+    /// `max_ledger_shreds` by showing whether idle or busy slots dominate.
+    pub shred_histogram_interval: Option<u64>,
+    /// When set, the mean on-disk size of a data shred is sampled from the
+    /// last few full slots below the root on every Nth check, handed to
+    /// `ShredSizeEstimator::calibrate` and reported in a
+    /// `ledger_cleanup_bytes_per_shred` datapoint. This lets operators check
+    /// the size assumed by `DEFAULT_BYTES_PER_SHRED` against their ledger.
+    pub shred_size_calibration_interval: Option<u64>,
+    /// Scanning the blockstore for the slots it holds should be quick; a
+    /// `ledger_cleanup_slow_scan` datapoint is emitted whenever it takes
+    /// longer than this, as it likely indicates degraded iterator
+    /// performance that will also affect replay.
+    pub slow_scan_threshold: Duration,
+    /// Sample the blockstore's storage size around each cleanup and purge
+    /// and report it, along with the size of each column, in the
+    /// `ledger_disk_utilization` datapoints. Determining the storage size
+    /// walks RocksDB's live files, so nodes that do not ship these metrics
+    /// can turn this off. Byte-based retention limits are unaffected.
+    pub report_disk_metrics: bool,
+    /// The clock used to decide when a cleanup is due and to wait between
+    /// checks of the exit flag.
+    pub clock: Arc<dyn Clock + Send + Sync>,
+}
+
+impl Default for BlockstoreCleanupConfig {
+    fn default() -> Self {
+        Self {
+            max_ledger_shreds: DEFAULT_MAX_LEDGER_SHREDS,
+            retain_all: false,
+            hysteresis: DEFAULT_HYSTERESIS,
+            warn_threshold_shreds: None,
+            max_ledger_bytes: None,
+            shred_size_estimator: None,
+            shred_count_estimator: None,
+            max_ledger_duration: None,
+            min_free_disk_bytes: None,
+            ledger_volume_capacity_bytes: None,
+            cleanup_policy: None,
+            ms_per_slot: DEFAULT_MS_PER_SLOT,
+            purge_interval: DEFAULT_CLEANUP_SLOT_INTERVAL,
+            adaptive_purge_interval: None,
+            min_wall_interval: Duration::ZERO,
+            min_scan_interval: Duration::ZERO,
+            min_slots_to_retain: 0,
+            repair_retention_slots: DEFAULT_REPAIR_RETENTION_SLOTS,
+            protected_slot_floor: None,
+            confirmation_lag: 0,
+            pinned_slots: Arc::default(),
+            purge_progress: Arc::default(),
+            lowest_existing_slot: Arc::default(),
+            epoch_schedule: None,
+            on_purge: None,
+            purge_event_sender: None,
+            purge_veto: None,
+            snapshot_in_progress: None,
+            max_ledger_shreds_provider: None,
+            archiver: None,
+            dry_run: false,
+            purge_type: PurgeType::CompactionFilter,
+            metadata_retention_slots: None,
+            compact_after_purge: false,
+            compaction_columns: CompactionColumn::SHREDS.to_vec(),
+            max_slots_per_purge: None,
+            max_purge_bytes_per_sec: None,
+            exit_poll_interval: Duration::from_secs(1),
+            metrics_settle_delay: None,
+            purge_base_backoff: DEFAULT_PURGE_BASE_BACKOFF,
+            purge_max_backoff: DEFAULT_PURGE_MAX_BACKOFF,
+            thread_name_prefix: "solBstore".to_string(),
+            thread_niceness_adj: 0,
+            purge_on_start: false,
+            persist_last_purge_slot: false,
+            idle_threshold: DEFAULT_IDLE_THRESHOLD,
+            stall_threshold: DEFAULT_STALL_THRESHOLD,
+            shred_histogram_interval: None,
+            shred_size_calibration_interval: None,
+            slow_scan_threshold: DEFAULT_SLOW_SCAN_THRESHOLD,
+            report_disk_metrics: true,
+            clock: Arc::new(SystemClock),
+        }
+    }
+}
+
+impl BlockstoreCleanupConfig {
+    /// Returns `hysteresis`, clamped to \[0.0, 0.5\].
+    pub fn hysteresis(&self) -> f64 {
+        if self.hysteresis.is_nan() {
+            0.0
+        } else {
+            self.hysteresis.clamp(0.0, 0.5)
+        }
+    }
+
+    /// Returns the number of live data shreds to clean down to once
+    /// `max_ledger_shreds` has been exceeded.
+    fn target_ledger_shreds(&self) -> u64 {
+        (self.max_ledger_shreds as f64 * (1.0 - self.hysteresis())) as u64
+    }
+
+    /// Returns the number of slots below the root covered by
+    /// `max_ledger_duration`, if set.
+    pub fn max_ledger_slot_window(&self) -> Option<u64> {
+        self.max_ledger_duration.map(|duration| {
+            let num_slots = duration.as_millis() / u128::from(self.ms_per_slot.max(1));
+            u64::try_from(num_slots).unwrap_or(u64::MAX)
+        })
+    }
+
+    /// Returns how often the service should check whether a cleanup is due.
+    ///
+    /// The purge interval can be roughly equated to a time interval. So,
+    /// scale how often we check for cleanup with the interval. Doing so will
+    /// avoid wasted checks when we know that the latest root could not have
+    /// advanced far enough.
+    ///
+    /// Given that the timing of new slots/roots is not exact, divide by 10 to
+    /// avoid a long wait incase a check occurs just before the interval has
+    /// elapsed.
+    fn loop_limiter(&self) -> Duration {
+        Duration::from_millis(self.purge_interval.saturating_mul(self.ms_per_slot) / 10)
+    }
+
+    /// Returns the waits before each retry of a failed cleanup, doubling
+    /// from `purge_base_backoff` up to `purge_max_backoff`.
+    fn purge_backoffs(&self) -> impl Iterator<Item = Duration> {
+        let purge_max_backoff = self.purge_max_backoff;
+        std::iter::successors(
+            (!self.purge_base_backoff.is_zero()).then_some(self.purge_base_backoff),
+            |backoff| backoff.checked_mul(2),
+        )
+        .take_while(move |backoff| *backoff <= purge_max_backoff)
+    }
+}
+
+/// The bounds on the purge interval, in slots, for
+/// `BlockstoreCleanupConfig::adaptive_purge_interval`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct AdaptivePurgeInterval {
+    pub min_interval: u64,
+    pub max_interval: u64,
+}
+
+impl AdaptivePurgeInterval {
+    /// Returns the purge interval that leaves room for about two cleanups
+    /// before `growth_per_slot` would take the blockstore from
+    /// `remaining_shreds` to `max_ledger_shreds`.
+    fn purge_interval(
+        &self,
+        max_ledger_shreds: u64,
+        remaining_shreds: u64,
+        growth_per_slot: u64,
+    ) -> u64 {
+        let max_interval = self.max_interval.max(self.min_interval);
+        let headroom = max_ledger_shreds.saturating_sub(remaining_shreds);
+        headroom
+            .checked_div(growth_per_slot)
+            .map(|num_slots| num_slots / 2)
+            .unwrap_or(max_interval)
+            .clamp(self.min_interval, max_interval)
+    }
+}
+
+/// Tracks the number of live data shreds left after each cleanup, so that
+/// the growth of the blockstore can be measured at the next one.
+#[derive(Debug, Default)]
+struct ShredGrowth {
+    last_cleanup: Option<(Slot, u64)>,
+}
+
+impl ShredGrowth {
+    /// Records a cleanup at `root` and returns the mean number of shreds
+    /// added per slot since the previous one, along with the estimated
+    /// number of shreds left after this one.
+    fn record(&mut self, root: Slot, stats: &CleanupStats) -> Option<(u64, u64)> {
+        let shreds_per_slot = stats.total_shreds.checked_div(stats.total_slots);
+        let remaining_shreds = if stats.purged {
+            // The scan precedes the purge, so estimate what the purge removed
+            shreds_per_slot.map(|shreds_per_slot| {
+                stats
+                    .total_shreds
+                    .saturating_sub(stats.slots_purged.saturating_mul(shreds_per_slot))
+            })
+        } else {
+            Some(stats.total_shreds)
+        };
+        let last_cleanup = self.last_cleanup.take();
+        let remaining_shreds = remaining_shreds?;
+        self.last_cleanup = Some((root, remaining_shreds));
+        let (last_root, last_remaining_shreds) = last_cleanup?;
+        let growth_per_slot = stats
+            .total_shreds
+            .saturating_sub(last_remaining_shreds)
+            .checked_div(root.checked_sub(last_root)?)?;
+        Some((growth_per_slot, remaining_shreds))
+    }
+}
+
+/// The outcome of a single `cleanup_ledger` call.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct CleanupStats {
+    /// Whether any slots were purged.
+    pub purged: bool,
+    /// The highest slot that was purged; only meaningful if `purged` is set.
+    pub lowest_cleanup_slot: Slot,
+    /// The estimated number of live data shreds in the blockstore.
+    pub total_shreds: u64,
+    /// The number of slots spanned by the blockstore before the cleanup.
+    pub total_slots: u64,
+    /// The number of slots with data that were purged.
+    pub slots_purged: u64,
+    /// The estimated number of live data shreds that were purged, based on
+    /// the mean number of shreds per slot.
+    pub shreds_purged: u64,
+    /// The time spent purging slots, including `slot_update_duration` and
+    /// `throttle_duration`.
+    pub purge_duration: Duration,
+    /// The time spent waiting between purge steps to stay within
+    /// `BlockstoreCleanupConfig::max_purge_bytes_per_sec`.
+    pub throttle_duration: Duration,
+    /// The total time the cleanup occupied the cleanup thread, during which
+    /// no other cleanup could start.
+    pub cleanup_duration: Duration,
+    /// The time spent determining which slots to clean.
+    pub scan_duration: Duration,
+    /// The time spent updating the lowest cleanup slot and max expired slot.
+    pub slot_update_duration: Duration,
+    /// The time spent compacting the purged slots; see
+    /// `BlockstoreCleanupConfig::compact_after_purge`.
+    pub compaction_duration: Duration,
+    /// The change in the blockstore's storage size from just before the
+    /// purge to just after it (and the compaction, if enabled). Unlike the
+    /// change over the whole cleanup, this excludes most of the growth from
+    /// concurrent inserts. Positive values are bytes freed. Not sampled
+    /// unless `BlockstoreCleanupConfig::report_disk_metrics` is set.
+    pub purge_disk_utilization_delta: Option<i64>,
+    /// The retention limit that determined the number of slots to clean, if
+    /// any.
+    pub limit: Option<RetentionLimit>,
+    /// Whether the `PurgeEvent` for this cleanup was dropped because the
+    /// channel was full.
+    pub purge_event_dropped: bool,
+    /// Whether `BlockstoreCleanupConfig::purge_veto` or
+    /// `BlockstoreCleanupConfig::snapshot_in_progress` deferred the purge.
+    pub purge_vetoed: bool,
+    /// The number of times the cleanup was retried after failing; see
+    /// `BlockstoreCleanupConfig::purge_base_backoff`.
+    pub purge_retries: u64,
+    /// The number of slots between the root and the highest purged slot
+    /// after the cleanup, reported whether or not anything was purged.
+    pub retained_slots: u64,
+    /// Whether the purge was skipped because the blockstore had already been
+    /// purged through the cutoff.
+    pub noop: bool,
+    /// Whether the blockstore was expected to remain above
+    /// `max_ledger_shreds`; see `SlotsToClean::over_budget`.
+    pub over_budget: bool,
+    /// Whether the cleanup failed; it is retried on the next check.
+    pub failed: bool,
+    /// Whether the cleanup scanned the blockstore; see
+    /// `SlotsToClean::scanned`.
+    pub scanned: bool,
+    /// Whether the slots to clean were handed to
+    /// `BlockstoreCleanupConfig::archiver`; a retry doesn't archive them
+    /// again.
+    pub archived: bool,
+}
+
+impl CleanupStats {
+    /// Returns the rate at which slots were purged; 0 if nothing was purged.
+    pub fn slots_purged_per_sec(&self) -> f64 {
+        Self::purge_rate(self.slots_purged, self.purge_duration)
+    }
+
+    /// Returns the rate at which live data shreds were purged; 0 if nothing
+    /// was purged.
+    pub fn shreds_purged_per_sec(&self) -> f64 {
+        Self::purge_rate(self.shreds_purged, self.purge_duration)
+    }
+
+    fn purge_rate(count: u64, purge_duration: Duration) -> f64 {
+        if count == 0 {
+            return 0.0;
+        }
+        // Very quick purges would otherwise report absurdly high rates
+        count as f64 / purge_duration.max(Duration::from_millis(1)).as_secs_f64()
+    }
+}
+
+/// State that is updated by the cleanup thread and exposed by
+/// `BlockstoreCleanupService`.
+#[derive(Default)]
+struct CleanupState {
+    paused: AtomicBool,
+    cleanup_requested: AtomicBool,
+    last_purge_slot: AtomicU64,
+    lowest_cleanup_slot: AtomicU64,
+    purge_events_dropped: AtomicU64,
+    peak_total_shreds: AtomicU64,
+    lifetime_shreds_purged: AtomicU64,
+    // -1 until the blockstore has been seen to grow
+    time_to_full_secs: AtomicI64,
+    max_ledger_shreds: AtomicU64,
+    over_budget: AtomicBool,
+    // Milliseconds from the start of the service to the last cleanup that
+    // scanned or purged the blockstore without error
+    last_cleanup_ms: AtomicU64,
+    // Ranges queued by purge_range_now(), oldest first
+    pending_purges: Mutex<VecDeque<(Slot, Slot)>>,
+}
+
+impl CleanupState {
+    fn record_cleanup(&self, last_purge_slot: Slot, stats: &CleanupStats, elapsed: Duration) {
+        // Failed, vetoed and skipped cleanups leave the blockstore as it was,
+        // so they don't count towards the health of the service
+        if !stats.failed && !stats.purge_vetoed && (stats.scanned || stats.purged) {
+            self.last_cleanup_ms
+                .store(elapsed.as_millis() as u64, Ordering::Relaxed);
+        }
+        // Cleanups that did not scan the blockstore leave the flag as is
+        if stats.scanned {
+            self.over_budget.store(stats.over_budget, Ordering::Relaxed);
+        }
+        if stats.purged {
+            self.lowest_cleanup_slot
+                .store(stats.lowest_cleanup_slot, Ordering::Relaxed);
+        }
+        self.last_purge_slot
+            .store(last_purge_slot, Ordering::Relaxed);
+        if stats.purge_event_dropped {
+            self.purge_events_dropped.fetch_add(1, Ordering::Relaxed);
+        }
+        if stats.shreds_purged > 0 {
+            let lifetime_shreds_purged = self
+                .lifetime_shreds_purged
+                .fetch_add(stats.shreds_purged, Ordering::Relaxed)
+                .saturating_add(stats.shreds_purged);
+            datapoint_info!(
+                "ledger_cleanup_lifetime_shreds_purged",
+                ("lifetime_shreds_purged", lifetime_shreds_purged, i64),
+            );
+        }
+        let peak_total_shreds = self
+            .peak_total_shreds
+            .fetch_max(stats.total_shreds, Ordering::Relaxed);
+        if stats.total_shreds > peak_total_shreds {
+            datapoint_info!(
+                "ledger_cleanup_peak_shreds",
+                ("peak_total_shreds", stats.total_shreds, i64),
+            );
+        }
+    }
+}
+
+/// The cleanup thread of `BlockstoreCleanupService`, which runs the steps of
+/// each iteration of its loop until `exit` is set.
+struct CleanupLoop {
+    blockstore: Arc<Blockstore>,
+    config: BlockstoreCleanupConfig,
+    exit: Arc<AtomicBool>,
+    state: Arc<CleanupState>,
+    start: Instant,
+    last_purge_slot: Slot,
+    last_purge_time: Option<Instant>,
+    last_scan_time: Option<Instant>,
+    last_check_time: Instant,
+    last_root: Slot,
+    last_root_time: Instant,
+    num_checks: u64,
+    shred_growth: ShredGrowth,
+    loop_limiter: Duration,
+}
+
+impl CleanupLoop {
+    fn run(mut self) {
+        self.start_up();
+        while !self.exit.load(Ordering::Relaxed) {
+            // Requested purges are performed even while paused
+            BlockstoreCleanupService::purge_requested_ranges(
+                &self.blockstore,
+                &self.state,
+                &self.config,
+                &self.exit,
+            );
+            if let Some(cleanup_requested) = self.cleanup_due() {
+                self.track_root();
+                self.run_cleanup(cleanup_requested);
+                self.report_periodic_metrics();
+                self.last_check_time = self.config.clock.now();
+            }
+            // Only sleep for exit_poll_interval instead of loop_limiter so
+            // that this thread can respond to the exit flag in a timely manner
+            self.config.clock.sleep(self.config.exit_poll_interval);
+        }
+    }
+
+    /// Logs the settings, runs the cleanup on start if
+    /// `config.purge_on_start` is set, and clamps the adaptive purge
+    /// interval.
+    fn start_up(&mut self) {
+        let config = &self.config;
+        if config.thread_niceness_adj != 0 {
+            if let Err(err) = renice_this_thread(config.thread_niceness_adj) {
+                warn!("Unable to renice BlockstoreCleanupService thread: {err}");
+            }
+        }
+        if config.hysteresis() != config.hysteresis {
+            warn!(
+                "Clamping Blockstore cleanup hysteresis of {} to {}",
+                config.hysteresis,
+                config.hysteresis(),
+            );
+        }
+        info!(
+            "BlockstoreCleanupService has started with max ledger shreds={}, max ledger \
+             bytes={:?}, max ledger duration={:?}, purge interval={}",
+            config.max_ledger_shreds,
+            config.max_ledger_bytes,
+            config.max_ledger_duration,
+            config.purge_interval,
+        );
+        BlockstoreCleanupService::update_lowest_existing_slot(&self.blockstore, config);
+        if config.purge_on_start {
+            // Clean up against the current root right away instead of
+            // waiting for it to advance by purge_interval
+            let start_config = BlockstoreCleanupConfig {
+                purge_interval: 0,
+                ..config.clone()
+            };
+            let prev_last_purge_slot = self.last_purge_slot;
+            let stats = BlockstoreCleanupService::cleanup_ledger_with_config(
+                &self.blockstore,
+                &start_config,
+                &mut self.last_purge_slot,
+                &self.exit,
+            );
+            self.record_cleanup(prev_last_purge_slot, &stats);
+            self.last_check_time = self.config.clock.now();
+        }
+        // The purge interval is adjusted as the service runs if it is
+        // adaptive
+        if let Some(bounds) = &self.config.adaptive_purge_interval {
+            self.config.purge_interval = self.config.purge_interval.clamp(
+                bounds.min_interval,
+                bounds.max_interval.max(bounds.min_interval),
+            );
+        }
+        self.loop_limiter = self.config.loop_limiter();
+    }
+
+    /// Returns whether a cleanup should run now, and if so, whether it was
+    /// requested by `request_cleanup_now()`.
+    fn cleanup_due(&self) -> Option<bool> {
+        let config = &self.config;
+        let paused = self.state.paused.load(Ordering::Relaxed);
+        // A requested cleanup stays pending while paused
+        let cleanup_requested =
+            !paused && self.state.cleanup_requested.swap(false, Ordering::Relaxed);
+        // The check time is not updated while paused so that a cleanup
+        // happens as soon as the service is resumed
+        let due = cleanup_requested
+            || (config.clock.now().duration_since(self.last_check_time) > self.loop_limiter
+                && !paused
+                && BlockstoreCleanupService::min_wall_interval_elapsed(
+                    self.last_purge_time,
+                    config,
+                )
+                && BlockstoreCleanupService::min_scan_interval_elapsed(
+                    self.last_scan_time,
+                    &self.blockstore,
+                    config,
+                ));
+        due.then_some(cleanup_requested)
+    }
+
+    /// Notes when the root last advanced, reporting how long it has been
+    /// idle if it hasn't since the previous check.
+    fn track_root(&mut self) {
+        let root = self.blockstore.max_root();
+        if root != self.last_root {
+            self.last_root = root;
+            self.last_root_time = self.config.clock.now();
+        } else {
+            BlockstoreCleanupService::report_idle(
+                root,
+                self.config.clock.now().duration_since(self.last_root_time),
+                self.config.idle_threshold,
+            );
+        }
+    }
+
+    /// Runs a cleanup with the current `max_ledger_shreds`. A requested
+    /// cleanup skips the purge interval; the retention limits and floors
+    /// still apply.
+    fn run_cleanup(&mut self, cleanup_requested: bool) {
+        self.config.max_ledger_shreds = self.state.max_ledger_shreds.load(Ordering::Relaxed);
+        let prev_last_purge_slot = self.last_purge_slot;
+        let stats = if cleanup_requested {
+            info!("Running requested Blockstore cleanup");
+            datapoint_info!("ledger_cleanup_requested", ("count", 1, i64));
+            let requested_config = BlockstoreCleanupConfig {
+                purge_interval: 0,
+                ..self.config.clone()
+            };
+            BlockstoreCleanupService::cleanup_ledger_with_config(
+                &self.blockstore,
+                &requested_config,
+                &mut self.last_purge_slot,
+                &self.exit,
+            )
+        } else {
+            BlockstoreCleanupService::cleanup_ledger_with_config(
+                &self.blockstore,
+                &self.config,
+                &mut self.last_purge_slot,
+                &self.exit,
+            )
+        };
+        if stats.scanned {
+            self.last_scan_time = Some(self.config.clock.now());
+        }
+        self.record_cleanup(prev_last_purge_slot, &stats);
+        // Only cleanups that scanned the blockstore report its size
+        if stats.scanned {
+            self.record_growth(&stats);
+        }
+    }
+
+    /// Persists the last purge slot if the cleanup moved it, and exposes the
+    /// outcome of the cleanup through `BlockstoreCleanupService`.
+    fn record_cleanup(&mut self, prev_last_purge_slot: Slot, stats: &CleanupStats) {
+        if self.last_purge_slot != prev_last_purge_slot {
+            self.last_purge_time = Some(self.config.clock.now());
+            BlockstoreCleanupService::store_last_purge_slot(
+                &self.blockstore,
+                &self.config,
+                self.last_purge_slot,
+            );
+        }
+        self.state.record_cleanup(
+            self.last_purge_slot,
+            stats,
+            self.config.clock.now().duration_since(self.start),
+        );
+    }
+
+    /// Projects when the blockstore will reach `max_ledger_shreds` from its
+    /// growth since the previous scan, and adjusts the purge interval to it
+    /// if the interval is adaptive.
+    fn record_growth(&mut self, stats: &CleanupStats) {
+        let config = &mut self.config;
+        let Some((growth_per_slot, remaining_shreds)) =
+            self.shred_growth.record(self.last_purge_slot, stats)
+        else {
+            return;
+        };
+        let time_to_full = BlockstoreCleanupService::projected_time_to_full(
+            config.max_ledger_shreds,
+            remaining_shreds,
+            growth_per_slot,
+            config.ms_per_slot,
+        );
+        let time_to_full_secs = time_to_full
+            .map(|time_to_full| time_to_full.as_secs() as i64)
+            .unwrap_or(-1);
+        self.state
+            .time_to_full_secs
+            .store(time_to_full_secs, Ordering::Relaxed);
+        datapoint_info!(
+            "ledger_cleanup_time_to_full_secs",
+            ("time_to_full_secs", time_to_full_secs, i64),
+            ("growth_per_slot", growth_per_slot, i64),
+            ("remaining_shreds", remaining_shreds, i64),
+        );
+
+        if let Some(bounds) = config.adaptive_purge_interval {
+            let purge_interval =
+                bounds.purge_interval(config.max_ledger_shreds, remaining_shreds, growth_per_slot);
+            if purge_interval != config.purge_interval {
+                info!(
+                    "Adjusting Blockstore purge interval from {} to {purge_interval} slots for \
+                     growth of {growth_per_slot} shreds per slot",
+                    config.purge_interval,
+                );
+                config.purge_interval = purge_interval;
+                self.loop_limiter = config.loop_limiter();
+            }
+        }
+    }
+
+    /// Reports the shred histogram and calibrates the shred size every so
+    /// many checks, as configured.
+    fn report_periodic_metrics(&mut self) {
+        self.num_checks += 1;
+        if let Some(interval) = self.config.shred_histogram_interval {
+            if self.num_checks % interval.max(1) == 0 {
+                BlockstoreCleanupService::report_shred_histogram(&self.blockstore);
+            }
+        }
+        if let Some(interval) = self.config.shred_size_calibration_interval {
+            if self.num_checks % interval.max(1) == 0 {
+                BlockstoreCleanupService::calibrate_shred_size(&self.blockstore, &self.config);
+            }
+        }
+    }
+}
+
+pub struct BlockstoreCleanupService {
+    t_cleanup: JoinHandle<()>,
+    state: Arc<CleanupState>,
+    pinned_slots: Arc<RwLock<HashSet<Slot>>>,
+    purge_progress: Arc<AtomicU64>,
+    lowest_existing_slot: Arc<AtomicU64>,
+    // The config that the cleanup thread was started with, for scan_now()
+    scan_config: BlockstoreCleanupConfig,
+    clock: Arc<dyn Clock + Send + Sync>,
+    start: Instant,
+    stall_threshold: Duration,
+}
+
+impl BlockstoreCleanupService {
+    pub fn new(blockstore: Arc<Blockstore>, max_ledger_shreds: u64, exit: Arc<AtomicBool>) -> Self {
+        let config = BlockstoreCleanupConfig {
+            max_ledger_shreds,
+            ..BlockstoreCleanupConfig::default()
+        };
+        Self::new_with_config(blockstore, config, exit)
+    }
+
+    /// Starts the cleanup thread with the retention settings in `config`.
+    ///
+    /// Which columns a purge clears is selected with `config.purge_type`.
+    /// An RPC node that serves transaction history over the same window as
+    /// its blocks wants `PurgeType::Exact`, so the `transaction_status` and
+    /// `address_signatures` entries for purged slots are deleted on the same
+    /// schedule as the slots themselves. With the default
+    /// `PurgeType::CompactionFilter`, those entries remain queryable until
+    /// RocksDB compacts the files holding them. A node that must keep
+    /// transaction history indefinitely should not run this service against
+    /// that blockstore at all, as both purge types eventually remove it.
+    pub fn new_with_config(
+        blockstore: Arc<Blockstore>,
+        config: BlockstoreCleanupConfig,
+        exit: Arc<AtomicBool>,
+    ) -> Self {
+        let last_purge_slot = Self::load_last_purge_slot(&blockstore, &config);
+        let pinned_slots = config.pinned_slots.clone();
+        let purge_progress = config.purge_progress.clone();
+        let lowest_existing_slot = config.lowest_existing_slot.clone();
+        let scan_config = config.clone();
+        let clock = config.clock.clone();
+        let start = config.clock.now();
+        let stall_threshold = config.stall_threshold;
+        let state = Arc::<CleanupState>::default();
+        state
+            .last_purge_slot
+            .store(last_purge_slot, Ordering::Relaxed);
+        state
+            .max_ledger_shreds
+            .store(config.max_ledger_shreds, Ordering::Relaxed);
+        state.time_to_full_secs.store(-1, Ordering::Relaxed);
+
+        let thread_name = format!("{}Clean", config.thread_name_prefix);
+        let cleanup_loop = CleanupLoop {
+            last_root: blockstore.max_root(),
+            blockstore,
+            exit,
+            state: state.clone(),
+            start,
+            last_purge_slot,
+            last_purge_time: None,
+            last_scan_time: None,
+            last_check_time: start,
+            last_root_time: start,
+            num_checks: 0,
+            shred_growth: ShredGrowth::default(),
+            loop_limiter: config.loop_limiter(),
+            config,
+        };
+        let t_cleanup = Builder::new()
+            .name(thread_name)
+            .spawn(move || {
+                let result = panic::catch_unwind(AssertUnwindSafe(move || cleanup_loop.run()));
+                // The exit flag is the only way out of the loop, so the thread
+                // either stopped as requested or panicked
+                match result {
+                    Ok(()) => {
+                        info!("BlockstoreCleanupService has stopped: exit requested");
+                        datapoint_info!("ledger_cleanup_stopped", ("reason", "exit", String));
+                    }
+                    Err(err) => {
+                        let message = err
+                            .downcast_ref::<&str>()
+                            .map(ToString::to_string)
+                            .or_else(|| err.downcast_ref::<String>().cloned())
+                            .unwrap_or_default();
+                        error!("BlockstoreCleanupService has stopped: panicked: {message}");
+                        datapoint_error!(
+                            "ledger_cleanup_stopped",
+                            ("reason", "error", String),
+                            ("error", message, String),
+                        );
+                        panic::resume_unwind(err);
+                    }
+                }
+            })
+            .unwrap();
+
+        Self {
+            t_cleanup,
+            state,
+            pinned_slots,
+            purge_progress,
+            lowest_existing_slot,
+            scan_config,
+            clock,
+            start,
+            stall_threshold,
+        }
+    }
+
+    /// Returns the highest slot purged by the service, or 0 if it has not
+    /// purged anything yet. Slots above it may still be available.
+    pub fn lowest_cleanup_slot(&self) -> Slot {
+        self.state.lowest_cleanup_slot.load(Ordering::Relaxed)
+    }
+
+    /// Returns the range of slots, \[`oldest`, `newest`\], that the blockstore
+    /// retains: those above the highest purged slot, up to the max root.
+    /// Answers "what is my retained window" without scanning the blockstore;
+    /// slots within the range may still be missing if they were skipped.
+    /// Returns `None` if no rooted slot is retained.
+    pub fn retained_range(&self, blockstore: &Blockstore) -> Option<(Slot, Slot)> {
+        // The blockstore releases slots before purging them, and may have been
+        // purged outside of the service, so its cutoff may be further along
+        let lowest_cleanup_slot = self
+            .lowest_cleanup_slot()
+            .max(blockstore.lowest_cleanup_slot());
+        let oldest = lowest_cleanup_slot.saturating_add(1);
+        let newest = blockstore.max_root();
+        (oldest <= newest).then_some((oldest, newest))
+    }
+
+    /// Returns the root at which the service last looked for slots to clean.
+    pub fn last_purge_slot(&self) -> Slot {
+        self.state.last_purge_slot.load(Ordering::Relaxed)
+    }
+
+    /// Returns the maximum number of live data shreds the service retains.
+    pub fn max_ledger_shreds(&self) -> u64 {
+        self.state.max_ledger_shreds.load(Ordering::Relaxed)
+    }
+
+    /// Changes the maximum number of live data shreds to retain, taking
+    /// effect from the next cleanup.
+    pub fn set_max_ledger_shreds(&self, max_ledger_shreds: u64) {
+        let prev_max_ledger_shreds = self
+            .state
+            .max_ledger_shreds
+            .swap(max_ledger_shreds, Ordering::Relaxed);
+        if prev_max_ledger_shreds != max_ledger_shreds {
+            info!("Changed max ledger shreds from {prev_max_ledger_shreds} to {max_ledger_shreds}");
+        }
+    }
+
+    /// Returns the highest number of live data shreds seen by any cleanup
+    /// since the service started. Cleanups see the blockstore at its
+    /// fullest, just before purging, so this is the peak disk usage to size
+    /// the ledger volume for.
+    pub fn peak_total_shreds(&self) -> u64 {
+        self.state.peak_total_shreds.load(Ordering::Relaxed)
+    }
+
+    /// Returns the estimated number of shreds purged by all of the cleanups
+    /// since the service started. Unlike `CleanupStats::total_shreds`, which
+    /// is the live count, this only ever grows, so it can be compared against
+    /// the wear on the ledger volume.
+    pub fn lifetime_shreds_purged(&self) -> u64 {
+        self.state.lifetime_shreds_purged.load(Ordering::Relaxed)
+    }
+
+    /// Returns how long the blockstore will take to reach
+    /// `max_ledger_shreds` if it keeps growing at the rate measured between
+    /// the last two cleanups that scanned it, or `None` if it is not growing
+    /// or has not been scanned twice yet.
+    pub fn time_to_full(&self) -> Option<Duration> {
+        u64::try_from(self.state.time_to_full_secs.load(Ordering::Relaxed))
+            .ok()
+            .map(Duration::from_secs)
+    }
+
+    /// Returns the number of `PurgeEvent`s that were dropped because the
+    /// channel was full.
+    pub fn purge_events_dropped(&self) -> u64 {
+        self.state.purge_events_dropped.load(Ordering::Relaxed)
+    }
+
+    /// Stops the service from cleaning up the ledger until `resume()` is
+    /// called. Roots continue to advance while paused, so the first cleanup
+    /// after resuming may have a large backlog to purge.
+    pub fn pause(&self) {
+        self.set_paused(true);
+    }
+
+    /// Resumes cleaning up the ledger after a call to `pause()`.
+    pub fn resume(&self) {
+        self.set_paused(false);
+    }
+
+    /// Prevents `slot` from being cleaned up until `unpin_slot()` is called.
+    /// No slot at or above a pinned slot is cleaned up either, so pinning an
+    /// old slot effectively stops the service from purging.
+    pub fn pin_slot(&self, slot: Slot) {
+        self.pinned_slots.write().unwrap().insert(slot);
+    }
+
+    /// Allows `slot` to be cleaned up again after a call to `pin_slot()`.
+    pub fn unpin_slot(&self, slot: Slot) {
+        self.pinned_slots.write().unwrap().remove(&slot);
+    }
+
+    /// Scans `blockstore` against its latest root with the service's
+    /// retention settings and returns what a cleanup would purge, for
+    /// diagnostics such as answering capacity questions from an admin RPC.
+    /// The scan runs on the calling thread and neither purges anything nor
+    /// updates the state of the cleanup thread.
+    pub fn scan_now(&self, blockstore: &Blockstore) -> SlotsToClean {
+        let max_ledger_shreds = match &self.scan_config.max_ledger_shreds_provider {
+            Some(max_ledger_shreds_provider) => max_ledger_shreds_provider(),
+            None => self.max_ledger_shreds(),
+        };
+        let config = BlockstoreCleanupConfig {
+            max_ledger_shreds,
+            ..self.scan_config.clone()
+        };
+        Self::find_slots_to_clean(blockstore, blockstore.max_root(), &config)
+    }
+
+    /// Asks the cleanup thread to check whether the ledger needs to be
+    /// cleaned up the next time it wakes up, without waiting for the root to
+    /// advance by `purge_interval` or for the loop limiter. This lets an
+    /// external signal, such as disk pressure reported by the OS, trigger a
+    /// cleanup early. The cleanup still honors the retention limits,
+    /// `min_slots_to_retain` and the slot floors, only happens once the root
+    /// has moved past the last purge slot, and waits until the service is
+    /// resumed if it is paused.
+    pub fn request_cleanup_now(&self) {
+        self.state.cleanup_requested.store(true, Ordering::Relaxed);
+    }
+
+    /// Queues \[`from`, `to`\] to be purged by the cleanup thread the next
+    /// time it wakes up, ahead of any regular cleanup, independently of the
+    /// retention limits and even if the service is paused. This is a
+    /// maintenance escape hatch for removing the oldest slots by hand, not a
+    /// replacement for regular cleanup. Only a range that is below the root
+    /// and starts at most one slot past the lowest cleanup slot is purged;
+    /// any other range is refused when its turn comes, with a warning.
+    pub fn purge_range_now(&self, from: Slot, to: Slot) {
+        if from > to {
+            warn!("Ignoring request to purge Blockstore slots [{from}, {to}]: empty range");
+            return;
+        }
+        info!("Queueing purge of Blockstore slots [{from}, {to}]");
+        self.state
+            .pending_purges
+            .lock()
+            .unwrap()
+            .push_back((from, to));
+    }
+
+    /// Returns the percentage of the current purge that has completed; see
+    /// `BlockstoreCleanupConfig::purge_progress`.
+    pub fn purge_progress(&self) -> u64 {
+        self.purge_progress.load(Ordering::Relaxed)
+    }
+
+    /// Returns the lowest slot that cleanups start from; see
+    /// `BlockstoreCleanupConfig::lowest_existing_slot`.
+    pub fn lowest_existing_slot(&self) -> Slot {
+        self.lowest_existing_slot.load(Ordering::Relaxed)
+    }
+
+    /// Returns a summary of whether the service is keeping the blockstore
+    /// within its limits.
+    pub fn health(&self) -> CleanupHealth {
+        if self.is_paused() {
+            return CleanupHealth::Paused;
+        }
+        let last_cleanup =
+            Duration::from_millis(self.state.last_cleanup_ms.load(Ordering::Relaxed));
+        let elapsed = self.clock.now().duration_since(self.start);
+        if elapsed.saturating_sub(last_cleanup) > self.stall_threshold {
+            CleanupHealth::Stalled
+        } else if self.state.over_budget.load(Ordering::Relaxed) {
+            CleanupHealth::OverBudget
+        } else {
+            CleanupHealth::Healthy
+        }
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.state.paused.load(Ordering::Relaxed)
+    }
+
+    fn set_paused(&self, paused: bool) {
+        if self.state.paused.swap(paused, Ordering::Relaxed) != paused {
+            info!(
+                "BlockstoreCleanupService has been {}",
+                if paused { "paused" } else { "resumed" }
+            );
+            datapoint_info!("ledger_cleanup_paused", ("paused", paused, bool));
+        }
+    }
+
+    /// Returns the lowest slot in the blockstore that has some data in it.
+    ///
+    /// This is equivalent to `Blockstore::lowest_slot()`, except that the
+    /// search begins at the blockstore's `lowest_cleanup_slot` or
+    /// `config.lowest_existing_slot`, whichever is higher. Everything older
+    /// has already been purged by this service or was never there, so
+    /// iterating from 0 would only walk over deleted keys that may not have
+    /// been compacted away yet.
+    fn lowest_slot(
+        blockstore: &Blockstore,
+        config: &BlockstoreCleanupConfig,
+    ) -> BlockstoreResult<Slot> {
+        let start_slot = blockstore
+            .lowest_cleanup_slot()
+            .max(config.lowest_existing_slot.load(Ordering::Relaxed));
+        Ok(blockstore
+            .slot_meta_iterator(start_slot)?
+            .find(|(slot, meta)| *slot > 0 && meta.received > 0)
+            .map(|(slot, _)| slot)
+            .unwrap_or_else(|| blockstore.max_root()))
+    }
+
+    /// Sets `config.lowest_existing_slot` to the lowest slot with a
+    /// `SlotMeta` at or above the blockstore's `lowest_cleanup_slot`, or to
+    /// `lowest_cleanup_slot` if there is no such slot. The value is left as
+    /// is if the blockstore can't be read.
+    fn update_lowest_existing_slot(blockstore: &Blockstore, config: &BlockstoreCleanupConfig) {
+        let lowest_cleanup_slot = blockstore.lowest_cleanup_slot();
+        match blockstore.slot_meta_iterator(lowest_cleanup_slot) {
+            Ok(mut slot_metas) => {
+                let lowest_existing_slot = slot_metas
+                    .next()
+                    .map(|(slot, _)| slot)
+                    .unwrap_or(lowest_cleanup_slot);
+                debug!("Lowest existing Blockstore slot is {lowest_existing_slot}");
+                config
+                    .lowest_existing_slot
+                    .store(lowest_existing_slot, Ordering::Relaxed);
+            }
+            Err(err) => warn!("Unable to find the lowest existing Blockstore slot: {err:?}"),
+        }
+    }
+
+    /// Determines which slots a cleanup at `root` would purge under the
+    /// retention settings in `config`, without modifying the blockstore.
+    /// `cleanup_ledger` proceeds with the cleanup if
+    /// `SlotsToClean::should_clean` is set; tooling may call this directly to
+    /// find out what a given configuration would purge.
+    pub fn find_slots_to_clean(
+        blockstore: &Blockstore,
+        root: Slot,
+        config: &BlockstoreCleanupConfig,
+    ) -> SlotsToClean {
+        Self::try_find_slots_to_clean(blockstore, root, config).unwrap_or_else(|err| {
+            warn!("Unable to scan the Blockstore for slots to clean: {err:?}");
+            SlotsToClean::not_scanned(0)
+        })
+    }
+
+    /// Same as `find_slots_to_clean`, but returns any error encountered
+    /// while scanning the blockstore instead of finding nothing to clean.
+    pub fn try_find_slots_to_clean(
+        blockstore: &Blockstore,
+        root: Slot,
+        config: &BlockstoreCleanupConfig,
+    ) -> BlockstoreResult<SlotsToClean> {
+        if config.retain_all {
+            debug!("Skipping Blockstore scan: retaining all slots");
+            return Ok(SlotsToClean::not_scanned(0));
+        }
+
+        // Whatever the retention limits, an empty blockstore has nothing to
+        // clean; the calculations below assume that it holds some slots.
+        if blockstore.highest_slot()?.is_none() {
+            debug!("Skipping Blockstore cleanup: the blockstore is empty");
+            return Ok(SlotsToClean::nothing(0, 0));
+        }
+
+        if let Some(policy) = &config.cleanup_policy {
+            let (slots_to_clean, lowest_cleanup_slot, total_shreds) =
+                policy.slots_to_clean(blockstore, root);
+            if !slots_to_clean {
+                return Ok(SlotsToClean::nothing(total_shreds, 0));
+            }
+            if lowest_cleanup_slot >= root {
+                // Left for clean_slots() to refuse, rather than quietly
+                // clamped to the root
+                return Ok(SlotsToClean {
+                    should_clean: true,
+                    lowest_cleanup_slot,
+                    total_shreds,
+                    total_slots: 0,
+                    limit: Some(RetentionLimit::Policy),
+                    over_budget: false,
+                    scanned: true,
+                });
+            }
+            let slots_to_clean = match Self::limit_cleanup_slot(lowest_cleanup_slot, root, config) {
+                Some(lowest_cleanup_slot) => {
+                    info!(
+                        "ledger_cleanup event=select to={lowest_cleanup_slot} \
+                         shreds={total_shreds} reason={}",
+                        RetentionLimit::Policy.as_str(),
+                    );
+                    SlotsToClean {
+                        should_clean: true,
+                        lowest_cleanup_slot,
+                        total_shreds,
+                        total_slots: 0,
+                        limit: Some(RetentionLimit::Policy),
+                        over_budget: false,
+                        scanned: true,
+                    }
+                }
+                None => SlotsToClean::nothing(total_shreds, 0),
+            };
+            return Ok(slots_to_clean);
+        }
+
+        if let Some(num_shreds) = Self::estimated_num_shreds(blockstore, config) {
+            let scan_threshold =
+                (config.max_ledger_shreds as f64 * SHRED_ESTIMATE_SCAN_THRESHOLD) as u64;
+            if num_shreds < scan_threshold {
+                Self::report_approaching_limit(num_shreds, config);
+                debug!(
+                    "Skipping Blockstore scan: an estimated {num_shreds} alive shreds is below \
+                     {scan_threshold}",
+                );
+                return Ok(SlotsToClean::not_scanned(num_shreds));
+            }
+        }
+
+        let mut iterate_time = Measure::start("iterate_time");
+        let data_shred_cf_name = DATA_SHRED_CF.to_string();
+
+        // The shred count comes from the SST file metadata rather than from
+        // the meta of every slot, so the scan holds one entry per live file
+        // and its memory use does not grow with the number of slots retained.
+        let live_files = blockstore.live_files_metadata()?;
+        let num_shreds = live_files
+            .iter()
+            .filter(|live_file| live_file.column_family_name == data_shred_cf_name)
+            .map(|file_meta| file_meta.num_entries)
+            .sum();
+
+        // Using the difference between the lowest and highest slot seen will
+        // result in overestimating the number of slots in the blockstore since
+        // there are likely to be some missing slots, such as when a leader is
+        // delinquent for their leader slots.
+        //
+        // With the below calculations, we will then end up underestimating the
+        // mean number of shreds per slot present in the blockstore which will
+        // result in cleaning more slots than necessary to get us
+        // below max_ledger_shreds.
+        //
+        // Given that the service runs on an interval, this is good because it
+        // means that we are building some headroom so the peak number of alive
+        // shreds doesn't get too large before the service's next run.
+        //
+        // Finally, we have a check to make sure that we don't purge any slots
+        // newer than the passed in root. This check is practically only
+        // relevant when a cluster has extended periods of not rooting slots.
+        // With healthy cluster operation, the minimum ledger size ensures
+        // that purged slots will be quite old in relation to the newest root.
+        let lowest_slot = Self::lowest_slot(blockstore, config)?;
+        let highest_slot = blockstore.highest_slot()?.unwrap_or(lowest_slot);
+        if highest_slot < lowest_slot {
+            error!(
+                "Skipping Blockstore cleanup: highest slot {highest_slot} < lowest slot \
+                 {lowest_slot}",
+            );
+            return Ok(SlotsToClean::nothing(num_shreds, 0));
+        }
+        // The + 1 ensures we count the correct number of slots. Additionally,
+        // it guarantees num_slots >= 1 for the subsequent division.
+        let num_slots = highest_slot - lowest_slot + 1;
+        iterate_time.stop();
+        if iterate_time.as_duration() > config.slow_scan_threshold {
+            warn!("Blockstore cleanup scan of {num_slots} slots was slow: {iterate_time}");
+            datapoint_warn!(
+                "ledger_cleanup_slow_scan",
+                ("iterate_us", iterate_time.as_us(), i64),
+                ("num_slots", num_slots, i64),
+            );
+        }
+        let mean_shreds_per_slot = num_shreds / num_slots;
+        info!(
+            "ledger_cleanup event=scan shreds={num_shreds} from={lowest_slot} \
+             to={highest_slot} mean_shreds_per_slot={mean_shreds_per_slot}",
+        );
+        Self::report_approaching_limit(num_shreds, config);
+
+        let mut num_slots_to_clean = 0;
+        let mut limit = None;
+        if num_shreds > config.max_ledger_shreds {
+            num_slots_to_clean =
+                Self::num_slots_to_clean(num_shreds, config.target_ledger_shreds(), num_slots);
+            limit = Some(RetentionLimit::Shreds);
+        }
+
+        if let Some(max_ledger_bytes) = config.max_ledger_bytes {
+            let num_bytes = match &config.shred_size_estimator {
+                Some(estimator) => {
+                    Ok(num_shreds.saturating_mul(estimator.avg_bytes_per_shred(blockstore)))
+                }
+                None => blockstore.storage_size(),
+            };
+            match num_bytes {
+                Ok(num_bytes) if num_bytes > max_ledger_bytes => {
+                    // Same estimation as above, but with the mean number of
+                    // bytes per slot; slots with more shreds are also likely
+                    // to take up more space on disk.
+                    let mean_bytes_per_slot = num_bytes / num_slots;
+                    info!(
+                        "Blockstore occupies {num_bytes} bytes, mean of {mean_bytes_per_slot} \
+                         bytes per slot",
+                    );
+                    let num_slots = (num_bytes - max_ledger_bytes + mean_bytes_per_slot - 1)
+                        .checked_div(mean_bytes_per_slot)
+                        .unwrap_or(0);
+                    if num_slots > num_slots_to_clean {
+                        num_slots_to_clean = num_slots;
+                        limit = Some(RetentionLimit::Bytes);
+                    }
+                }
+                Ok(_) => (),
+                Err(err) => warn!("Unable to determine Blockstore storage size: {err:?}"),
+            }
+        }
+
+        if let Some(min_free_disk_bytes) = config.min_free_disk_bytes {
+            match available_disk_space(blockstore.ledger_path()) {
+                Ok(free_bytes) if free_bytes < min_free_disk_bytes => {
+                    match blockstore.storage_size() {
+                        Ok(num_bytes) => {
+                            // Same estimation as for max_ledger_bytes, but
+                            // the shortfall in free space is what needs to
+                            // be reclaimed.
+                            let mean_bytes_per_slot = num_bytes / num_slots;
+                            info!(
+                                "Ledger volume has {free_bytes} bytes free, below the minimum \
+                                 of {min_free_disk_bytes} bytes; mean of {mean_bytes_per_slot} \
+                                 bytes per slot",
+                            );
+                            let num_slots = (min_free_disk_bytes - free_bytes)
+                                .saturating_add(mean_bytes_per_slot.saturating_sub(1))
+                                .checked_div(mean_bytes_per_slot)
+                                .unwrap_or(0);
+                            if num_slots > num_slots_to_clean {
+                                num_slots_to_clean = num_slots;
+                                limit = Some(RetentionLimit::FreeDiskSpace);
+                            }
+                        }
+                        Err(err) => warn!("Unable to determine Blockstore storage size: {err:?}"),
+                    }
+                }
+                Ok(_) => (),
+                Err(err) => warn!("Unable to determine free space on the ledger volume: {err:?}"),
+            }
+        }
+
+        if let Some(slot_window) = config.max_ledger_slot_window() {
+            // Everything older than the window below the root is eligible
+            if let Some(cutoff_slot) = root.checked_sub(slot_window.saturating_add(1)) {
+                if cutoff_slot >= lowest_slot {
+                    let num_slots = cutoff_slot - lowest_slot + 1;
+                    if num_slots > num_slots_to_clean {
+                        num_slots_to_clean = num_slots;
+                        limit = Some(RetentionLimit::Duration);
+                    }
+                }
+            }
+        }
+
+        if num_slots_to_clean == 0 {
+            return Ok(SlotsToClean::nothing(num_shreds, num_slots));
+        }
+
+        let lowest_cleanup_slot = lowest_slot + num_slots_to_clean - 1;
+        let limited_cleanup_slot = Self::limit_cleanup_slot(lowest_cleanup_slot, root, config);
+        let mut over_budget = false;
+        if num_shreds > config.max_ledger_shreds {
+            // Only the slots up to the limited cleanup slot are cleaned
+            let num_slots_cleaned = limited_cleanup_slot
+                .map(|slot| slot - lowest_slot + 1)
+                .unwrap_or(0);
+            let remaining_shreds =
+                num_shreds.saturating_sub(num_slots_cleaned.saturating_mul(mean_shreds_per_slot));
+            if remaining_shreds > config.max_ledger_shreds {
+                over_budget = true;
+                Self::report_over_budget(
+                    remaining_shreds,
+                    config.max_ledger_shreds,
+                    lowest_cleanup_slot,
+                    limited_cleanup_slot,
+                );
+            }
+        }
+        Ok(match limited_cleanup_slot {
+            Some(lowest_cleanup_slot) => {
+                info!(
+                    "ledger_cleanup event=select from={lowest_slot} to={lowest_cleanup_slot} \
+                     shreds={num_shreds} reason={}",
+                    limit.map_or("none", |limit| limit.as_str()),
+                );
+                SlotsToClean {
+                    should_clean: true,
+                    lowest_cleanup_slot,
+                    total_shreds: num_shreds,
+                    total_slots: num_slots,
+                    limit,
+                    over_budget,
+                    scanned: true,
+                }
+            }
+            None => SlotsToClean {
+                over_budget,
+                ..SlotsToClean::nothing(num_shreds, num_slots)
+            },
+        })
+    }
+
+    /// Returns how many of the oldest of `num_slots` slots, holding
+    /// `num_shreds` data shreds between them, to clean to get down to
+    /// `target_shreds`, assuming every slot holds as many shreds. The result
+    /// is rounded up, but never exceeds `num_slots`.
+    fn num_slots_to_clean(num_shreds: u64, target_shreds: u64, num_slots: u64) -> u64 {
+        if num_shreds == 0 {
+            return 0;
+        }
+        // Scale the excess by num_slots / num_shreds in one step rather than
+        // dividing by a truncated mean number of shreds per slot, which can
+        // call for more slots than there are
+        let num_shreds = u128::from(num_shreds);
+        let excess_shreds = num_shreds.saturating_sub(u128::from(target_shreds));
+        let num_slots_to_clean =
+            (excess_shreds * u128::from(num_slots) + num_shreds - 1) / num_shreds;
+        num_slots_to_clean as u64
+    }
+
+    /// Returns the mean on-disk size of the slots from `lowest_slot` up to
+    /// the highest slot in the blockstore.
+    fn mean_bytes_per_slot(blockstore: &Blockstore, lowest_slot: Slot) -> Option<u64> {
+        let num_bytes = blockstore
+            .storage_size()
+            .map_err(|err| warn!("Unable to determine Blockstore storage size: {err:?}"))
+            .ok()?;
+        let highest_slot = blockstore.highest_slot().ok().flatten()?;
+        let num_slots = highest_slot.saturating_sub(lowest_slot) + 1;
+        Some(num_bytes / num_slots)
+    }
+
+    /// Returns the configured estimate of the number of live data shreds, if
+    /// the shred count alone determines whether anything is cleaned.
+    fn estimated_num_shreds(
+        blockstore: &Blockstore,
+        config: &BlockstoreCleanupConfig,
+    ) -> Option<u64> {
+        let estimator = config.shred_count_estimator.as_ref()?;
+        if config.max_ledger_bytes.is_some()
+            || config.max_ledger_duration.is_some()
+            || config.min_free_disk_bytes.is_some()
+        {
+            return None;
+        }
+        estimator.estimated_num_shreds(blockstore)
+    }
+
+    /// Lowers `lowest_cleanup_slot` so that the cleanup stays below `root`
+    /// and preserves the slots protected by `config`.
+    ///
+    /// Returns `None` if no slots may be cleaned.
+    fn limit_cleanup_slot(
+        lowest_cleanup_slot: Slot,
+        root: Slot,
+        config: &BlockstoreCleanupConfig,
+    ) -> Option<Slot> {
+        // Ensure we don't cleanup the last root we saw, or anything past it
+        let Some(max_cleanup_slot) = root.checked_sub(1) else {
+            debug!("Skipping Blockstore cleanup: there are no slots below root 0");
+            return None;
+        };
+        let mut lowest_cleanup_slot = std::cmp::min(lowest_cleanup_slot, max_cleanup_slot);
+
+        if config.repair_retention_slots > 0 {
+            let Some(max_cleanup_slot) = root.checked_sub(config.repair_retention_slots) else {
+                info!(
+                    "Skipping Blockstore cleanup: root {root} is within the {} slots retained \
+                     for repair",
+                    config.repair_retention_slots,
+                );
+                return None;
+            };
+            if lowest_cleanup_slot > max_cleanup_slot {
+                info!(
+                    "Limiting Blockstore cleanup to slot {max_cleanup_slot} instead of \
+                     {lowest_cleanup_slot} to retain {} slots for repair",
+                    config.repair_retention_slots,
+                );
+                lowest_cleanup_slot = max_cleanup_slot;
+            }
+        }
+
+        if config.min_slots_to_retain > 0 {
+            let Some(max_cleanup_slot) = root.checked_sub(config.min_slots_to_retain) else {
+                info!(
+                    "Skipping Blockstore cleanup: root {root} is within min slots to retain {}",
+                    config.min_slots_to_retain,
+                );
+                return None;
+            };
+            if lowest_cleanup_slot > max_cleanup_slot {
+                info!(
+                    "Limiting Blockstore cleanup to slot {max_cleanup_slot} instead of \
+                     {lowest_cleanup_slot} to retain {} slots",
+                    config.min_slots_to_retain,
+                );
+                lowest_cleanup_slot = max_cleanup_slot;
+            }
+        }
+
+        if let Some(protected_slot_floor) = &config.protected_slot_floor {
+            let protected_slot_floor = protected_slot_floor.load(Ordering::Relaxed);
+            if lowest_cleanup_slot >= protected_slot_floor {
+                let Some(max_cleanup_slot) = protected_slot_floor.checked_sub(1) else {
+                    warn!("Deferring Blockstore cleanup: all slots are protected");
+                    return None;
+                };
+                warn!(
+                    "Limiting Blockstore cleanup to slot {max_cleanup_slot} instead of \
+                     {lowest_cleanup_slot} to preserve protected slots from \
+                     {protected_slot_floor}",
+                );
+                lowest_cleanup_slot = max_cleanup_slot;
+            }
+        }
+
+        let lowest_pinned_slot = config.pinned_slots.read().unwrap().iter().min().copied();
+        if let Some(lowest_pinned_slot) = lowest_pinned_slot {
+            if lowest_cleanup_slot >= lowest_pinned_slot {
+                let Some(max_cleanup_slot) = lowest_pinned_slot.checked_sub(1) else {
+                    warn!("Deferring Blockstore cleanup: slot 0 is pinned");
+                    return None;
+                };
+                warn!(
+                    "Limiting Blockstore cleanup to slot {max_cleanup_slot} instead of \
+                     {lowest_cleanup_slot} to preserve pinned slot {lowest_pinned_slot}",
+                );
+                lowest_cleanup_slot = max_cleanup_slot;
+            }
+        }
+
+        if let Some(epoch_schedule) = &config.epoch_schedule {
+            let epoch = epoch_schedule.get_epoch(lowest_cleanup_slot);
+            if lowest_cleanup_slot != epoch_schedule.get_last_slot_in_epoch(epoch) {
+                let Some(max_cleanup_slot) =
+                    epoch_schedule.get_first_slot_in_epoch(epoch).checked_sub(1)
+                else {
+                    info!("Skipping Blockstore cleanup: no complete epoch to clean");
+                    return None;
+                };
+                info!(
+                    "Limiting Blockstore cleanup to slot {max_cleanup_slot} instead of \
+                     {lowest_cleanup_slot} to retain all of epoch {epoch}",
+                );
+                lowest_cleanup_slot = max_cleanup_slot;
+            }
+        }
+
+        Some(lowest_cleanup_slot)
+    }
+
+    /// Checks for new roots and initiates a cleanup if the last cleanup was at
+    /// least `purge_interval` slots ago. A cleanup will no-op if the ledger
+    /// already has fewer than `max_ledger_shreds`; otherwise, the cleanup will
+    /// purge enough slots to get the ledger size below `max_ledger_shreds`.
+    ///
+    /// # Arguments
+    ///
+    /// - `max_ledger_shreds`: the number of shreds to keep since the new root.
+    /// - `last_purge_slot`: an both an input and output parameter indicating
+    ///   the id of the last purged slot.  As an input parameter, it works
+    ///   together with `purge_interval` on whether it is too early to perform
+    ///   ledger cleanup.  As an output parameter, it will be updated if this
+    ///   function actually performs the ledger cleanup.
+    /// - `purge_interval`: the minimum slot interval between two ledger
+    ///   cleanup.  When the max root fetched from the Blockstore minus
+    ///   `last_purge_slot` is fewer than `purge_interval`, the function will
+    ///   simply return `Ok` without actually running the ledger cleanup.
+    ///   In this case, `purge_interval` will remain unchanged.
+    ///
+    /// This only applies the shred limit; use `cleanup_ledger_with_config`
+    /// for the other retention limits, or to stop a long purge early.
+    ///
+    /// Returns a `CleanupStats` describing what the cleanup did; the stats
+    /// are left at their default values if the cleanup was skipped.
+    ///
+    /// Also see `blockstore::purge_slot`.
+    pub fn cleanup_ledger(
+        blockstore: &Arc<Blockstore>,
+        max_ledger_shreds: u64,
+        last_purge_slot: &mut u64,
+        purge_interval: u64,
+    ) -> CleanupStats {
+        let config = BlockstoreCleanupConfig {
+            max_ledger_shreds,
+            purge_interval,
+            hysteresis: 0.0,
+            repair_retention_slots: 0,
+            ..BlockstoreCleanupConfig::default()
+        };
+        Self::cleanup_ledger_with_config(
+            blockstore,
+            &config,
+            last_purge_slot,
+            &AtomicBool::new(false),
+        )
+    }
+
+    /// Same as `cleanup_ledger`, but applies all of the retention limits and
+    /// the purge interval in `config`.
+    ///
+    /// `exit` is checked between the steps of a purge that is split into
+    /// several, and while waiting between them, so that a long cleanup can
+    /// be stopped early. The purged slots are always a prefix of the slots
+    /// to clean, and the remainder is cleaned up by a later call.
+    pub fn cleanup_ledger_with_config(
+        blockstore: &Arc<Blockstore>,
+        config: &BlockstoreCleanupConfig,
+        last_purge_slot: &mut u64,
+        exit: &AtomicBool,
+    ) -> CleanupStats {
+        let root = blockstore.max_root();
+        Self::cleanup_ledger_at_root(blockstore, root, config, last_purge_slot, exit)
+    }
+
+    /// Same as `cleanup_ledger_with_config`, but cleans up relative to
+    /// `root` instead of the blockstore's latest root. This separates
+    /// finding the root from acting on it, for tooling and tests that want
+    /// to drive the cleanup from a root of their choosing.
+    pub fn cleanup_ledger_at_root(
+        blockstore: &Arc<Blockstore>,
+        root: Slot,
+        config: &BlockstoreCleanupConfig,
+        last_purge_slot: &mut u64,
+        exit: &AtomicBool,
+    ) -> CleanupStats {
+        if root < *last_purge_slot {
+            // Retention limits are relative to the root, so cleaning up
+            // based on a regressed root could purge too much
+            warn!(
+                "Skipping Blockstore cleanup: root {root} is below the last purge slot {}",
+                *last_purge_slot,
+            );
+            return CleanupStats::default();
+        }
+        if root.saturating_sub(*last_purge_slot) <= config.purge_interval {
+            debug!(
+                "Skipping Blockstore cleanup: root {root} is within the purge interval of {} \
+                 slots from {}",
+                config.purge_interval, *last_purge_slot,
+            );
+            return CleanupStats::default();
+        }
+        // The service polls for new roots rather than receiving each of them,
+        // so report how far the root moved since the previous cleanup. Lag
+        // that is consistently well beyond purge_interval means cleanups are
+        // taking too long to keep up with the cluster.
+        let root_lag = (*last_purge_slot > 0).then(|| root - *last_purge_slot);
+        let Some(cleanup_root) = root.checked_sub(config.confirmation_lag) else {
+            debug!(
+                "Skipping Blockstore cleanup: root {root} is within the confirmation lag of {} \
+                 slots",
+                config.confirmation_lag,
+            );
+            return CleanupStats::default();
+        };
+        let prev_last_purge_slot = *last_purge_slot;
+        *last_purge_slot = root;
+        let provided_config;
+        let config = match &config.max_ledger_shreds_provider {
+            Some(max_ledger_shreds_provider) => {
+                provided_config = BlockstoreCleanupConfig {
+                    max_ledger_shreds: max_ledger_shreds_provider(),
+                    ..config.clone()
+                };
+                &provided_config
+            }
+            None => config,
+        };
+        debug!(
+            "Cleaning up Blockstore with max ledger shreds of {}",
+            config.max_ledger_shreds
+        );
+        info!("ledger_cleanup event=check root={root} cleanup_root={cleanup_root}");
+
+        let mut cleanup_time = Measure::start("cleanup_ledger()");
+        let disk_metrics_pre = config.report_disk_metrics.then(|| {
+            (
+                blockstore.storage_size(),
+                Self::free_disk_space(blockstore, config),
+            )
+        });
+        let mut stats = CleanupStats::default();
+        let mut slots_to_clean = None;
+        let mut backoffs = config.purge_backoffs();
+        let result = loop {
+            // A retry resumes with the slots found by the first scan, after
+            // the last purge step that completed
+            let result = match slots_to_clean {
+                Some(slots_to_clean) => Ok(slots_to_clean),
+                None => Self::scan_slots(blockstore, cleanup_root, config, &mut stats),
+            };
+            let result = result.and_then(|found| {
+                slots_to_clean = Some(found);
+                Self::purge_scanned_slots(blockstore, cleanup_root, found, config, exit, &mut stats)
+            });
+            let Err(err) = &result else {
+                break result;
+            };
+            let Some(backoff) = backoffs.next().filter(|_| !exit.load(Ordering::Relaxed)) else {
+                break result;
+            };
+            warn!(
+                "Failed to clean up Blockstore data at root {root}, retrying in {backoff:?}: \
+                 {err:?}"
+            );
+            config.clock.sleep(backoff);
+            stats.purge_retries += 1;
+        };
+        if let Err(err) = result {
+            error!(
+                "Failed to clean up Blockstore data at root {root} after {} retries, will retry \
+                 on the next check: {err:?}",
+                stats.purge_retries,
+            );
+            datapoint_error!(
+                "ledger_cleanup_purge_error",
+                ("count", 1, i64),
+                ("root", root, i64),
+                ("retries", stats.purge_retries, i64),
+                ("error", format!("{err:?}"), String),
+            );
+            // Retry on the next check rather than waiting for the root to
+            // advance by another purge_interval
+            *last_purge_slot = prev_last_purge_slot;
+            stats.failed = true;
+        } else if stats.purge_vetoed {
+            *last_purge_slot = prev_last_purge_slot;
+        }
+
+        if let Some((disk_utilization_pre, free_disk_space_pre)) = disk_metrics_pre {
+            // Only cleanups that scanned the blockstore know its shred count
+            if stats.scanned {
+                if let Ok(actual_bytes) = &disk_utilization_pre {
+                    Self::report_estimated_bytes(
+                        blockstore,
+                        config,
+                        stats.total_shreds,
+                        *actual_bytes,
+                    );
+                }
+            }
+            if stats.purged {
+                Self::wait_for_storage_size_to_settle(blockstore, config, exit);
+            }
+            Self::report_disk_metrics(
+                blockstore,
+                config,
+                disk_utilization_pre,
+                free_disk_space_pre,
+                &stats,
+            );
+            Self::report_column_disk_metrics(blockstore);
+        }
+        // How much history is left to catch back up from after a restart;
+        // see DEFAULT_MAX_LEDGER_SHREDS
+        stats.retained_slots = root.saturating_sub(blockstore.lowest_cleanup_slot());
+        cleanup_time.stop();
+        stats.cleanup_duration = cleanup_time.as_duration();
+        // Purges run on the cleanup thread, so a long wait_us means that new
+        // roots are piling up behind the purge. The purge deletes whole key
+        // ranges, so RocksDB cannot tell how many keys went with them; the
+        // slots and shreds purged stand in for that, and unlike the disk
+        // utilization they don't wait on compaction.
+        datapoint_info!(
+            "ledger_cleanup_timing",
+            ("root_lag", root_lag, Option<i64>),
+            ("purge_interval", config.purge_interval, i64),
+            ("slot_window", config.max_ledger_slot_window(), Option<i64>),
+            ("retained_slots", stats.retained_slots, i64),
+            (
+                "retained_secs",
+                stats.retained_slots.saturating_mul(config.ms_per_slot) / 1_000,
+                i64
+            ),
+            ("wait_us", stats.cleanup_duration.as_micros(), i64),
+            ("scan_us", stats.scan_duration.as_micros(), i64),
+            ("purge_us", stats.purge_duration.as_micros(), i64),
+            ("throttle_us", stats.throttle_duration.as_micros(), i64),
+            (
+                "slot_update_us",
+                stats.slot_update_duration.as_micros(),
+                i64
+            ),
+            ("compaction_us", stats.compaction_duration.as_micros(), i64),
+            ("slots_purged", stats.slots_purged, i64),
+            ("shreds_purged", stats.shreds_purged, i64),
+            ("slots_purged_per_sec", stats.slots_purged_per_sec(), f64),
+            ("shreds_purged_per_sec", stats.shreds_purged_per_sec(), f64),
+            ("retries", stats.purge_retries, i64),
+        );
+        stats
+    }
+
+    /// Waits for up to `config.metrics_settle_delay` for the storage size to
+    /// stop changing, checking it every `config.exit_poll_interval`.
+    fn wait_for_storage_size_to_settle(
+        blockstore: &Blockstore,
+        config: &BlockstoreCleanupConfig,
+        exit: &AtomicBool,
+    ) {
+        let Some(metrics_settle_delay) = config.metrics_settle_delay else {
+            return;
+        };
+        let mut storage_size = blockstore.storage_size().ok();
+        let mut remaining = metrics_settle_delay;
+        while !remaining.is_zero() && !exit.load(Ordering::Relaxed) {
+            let wait = if config.exit_poll_interval.is_zero() {
+                remaining
+            } else {
+                remaining.min(config.exit_poll_interval)
+            };
+            config.clock.sleep(wait);
+            remaining -= wait;
+            let prev_storage_size = storage_size;
+            storage_size = blockstore.storage_size().ok();
+            if storage_size == prev_storage_size {
+                break;
+            }
+        }
+        debug!(
+            "Waited {:?} for the Blockstore storage size to settle",
+            metrics_settle_delay - remaining,
+        );
+    }
+
+    /// Runs a single cleanup at `root` on the calling thread, purging enough
+    /// of the oldest slots to bring the blockstore down to
+    /// `max_ledger_shreds`. This is meant for offline maintenance, so no
+    /// slots are retained for repair and the cleanup does not go below
+    /// `max_ledger_shreds` by the usual hysteresis.
+    pub fn purge_once(
+        blockstore: &Arc<Blockstore>,
+        root: Slot,
+        max_ledger_shreds: u64,
+    ) -> BlockstoreResult<CleanupStats> {
+        let config = BlockstoreCleanupConfig {
+            max_ledger_shreds,
+            hysteresis: 0.0,
+            repair_retention_slots: 0,
+            ..BlockstoreCleanupConfig::default()
+        };
+        let mut stats = CleanupStats::default();
+        Self::clean_slots(
+            blockstore,
+            root,
+            &config,
+            &AtomicBool::new(false),
+            &mut stats,
+        )?;
+        Ok(stats)
+    }
+
+    /// Purges every slot up to and including `target`, regardless of any
+    /// retention limit. Like a cleanup, this releases the slots through
+    /// `lowest_cleanup_slot` before purging them and only then advances the
+    /// max expired slot, so readers never see a partially purged slot.
+    pub fn purge_below(
+        blockstore: &Arc<Blockstore>,
+        target: Slot,
+        purge_type: PurgeType,
+    ) -> BlockstoreResult<()> {
+        let config = BlockstoreCleanupConfig {
+            purge_type,
+            ..BlockstoreCleanupConfig::default()
+        };
+        let lowest_slot = Self::lowest_slot(blockstore, &config)?;
+        Self::purge_slots(
+            blockstore,
+            0,
+            lowest_slot,
+            target,
+            &config,
+            &AtomicBool::new(false),
+            &mut CleanupStats::default(),
+        )?;
+        Ok(())
+    }
+
+    /// Purges the ranges queued by `purge_range_now()`, oldest first. This
+    /// runs ahead of the regular cleanup, so that the cleanup scans the
+    /// blockstore as the ranges left it.
+    fn purge_requested_ranges(
+        blockstore: &Blockstore,
+        state: &CleanupState,
+        config: &BlockstoreCleanupConfig,
+        exit: &AtomicBool,
+    ) {
+        let pending_purges = std::mem::take(&mut *state.pending_purges.lock().unwrap());
+        for (from_slot, to_slot) in pending_purges {
+            if let Err(err) = Self::purge_range(blockstore, from_slot, to_slot, config, exit) {
+                error!(
+                    "Failed to purge requested Blockstore slots [{from_slot}, {to_slot}]: {err:?}"
+                );
+                datapoint_error!(
+                    "ledger_cleanup_purge_range_error",
+                    ("from_slot", from_slot, i64),
+                    ("to_slot", to_slot, i64),
+                    ("error", format!("{err:?}"), String),
+                );
+            }
+        }
+    }
+
+    /// Purges \[`from_slot`, `to_slot`\] as requested by `purge_range_now()`.
+    ///
+    /// The range is purged like a cleanup: `lowest_cleanup_slot` is advanced
+    /// before the purge and the max expired slot after it, so readers never
+    /// see a partially purged slot. That only holds for a range that is below
+    /// the root and adjoins the slots that were already cleaned up, so any
+    /// other range is refused; purging it would remove slots that readers
+    /// still expect to find.
+    fn purge_range(
+        blockstore: &Blockstore,
+        from_slot: Slot,
+        to_slot: Slot,
+        config: &BlockstoreCleanupConfig,
+        exit: &AtomicBool,
+    ) -> BlockstoreResult<()> {
+        let lowest_cleanup_slot = blockstore.lowest_cleanup_slot();
+        let root = blockstore.max_root();
+        if to_slot >= root || from_slot > lowest_cleanup_slot.saturating_add(1) {
+            warn!(
+                "Refusing to purge requested Blockstore slots [{from_slot}, {to_slot}]: only \
+                 slots below the root {root} that follow the lowest cleanup slot \
+                 {lowest_cleanup_slot} can be purged",
+            );
+            datapoint_warn!(
+                "ledger_cleanup_purge_range_refused",
+                ("from_slot", from_slot, i64),
+                ("to_slot", to_slot, i64),
+                ("lowest_cleanup_slot", lowest_cleanup_slot, i64),
+                ("root", root, i64),
+            );
+            return Ok(());
+        }
+        if to_slot <= lowest_cleanup_slot {
+            info!(
+                "Requested Blockstore slots [{from_slot}, {to_slot}] were already purged through \
+                 slot {lowest_cleanup_slot}",
+            );
+            return Ok(());
+        }
+
+        let mut purge_time = Measure::start("purge_range");
+        Self::purge_slots(
+            blockstore,
+            from_slot,
+            from_slot,
+            to_slot,
+            config,
+            exit,
+            &mut CleanupStats::default(),
+        )?;
+        purge_time.stop();
+        info!("Purged requested Blockstore slots [{from_slot}, {to_slot}]. {purge_time}");
+        datapoint_info!(
+            "ledger_cleanup_purge_range",
+            ("from_slot", from_slot, i64),
+            ("to_slot", to_slot, i64),
+            ("purge_us", purge_time.as_us(), i64),
+        );
+        Ok(())
+    }
+
+    /// Finds the slots to clean at `root` and purges them; see
+    /// `purge_scanned_slots()`.
+    fn clean_slots(
+        blockstore: &Arc<Blockstore>,
+        root: Slot,
+        config: &BlockstoreCleanupConfig,
+        exit: &AtomicBool,
+        stats: &mut CleanupStats,
+    ) -> BlockstoreResult<()> {
+        let slots_to_clean = Self::scan_slots(blockstore, root, config, stats)?;
+        Self::purge_scanned_slots(blockstore, root, slots_to_clean, config, exit, stats)
+    }
+
+    /// Finds the slots to clean at `root`, recording what was found in
+    /// `stats`.
+    fn scan_slots(
+        blockstore: &Blockstore,
+        root: Slot,
+        config: &BlockstoreCleanupConfig,
+        stats: &mut CleanupStats,
+    ) -> BlockstoreResult<SlotsToClean> {
+        let mut scan_time = Measure::start("find_slots_to_clean()");
+        let slots_to_clean = Self::try_find_slots_to_clean(blockstore, root, config)?;
+        scan_time.stop();
+        stats.scanned = slots_to_clean.scanned;
+        stats.over_budget = slots_to_clean.over_budget;
+        stats.total_shreds = slots_to_clean.total_shreds;
+        stats.total_slots = slots_to_clean.total_slots;
+        stats.scan_duration = scan_time.as_duration();
+        stats.limit = slots_to_clean.limit;
+        Ok(slots_to_clean)
+    }
+
+    /// Purges the slots found by `scan_slots()` at `root`, unless
+    /// `config.dry_run` is set or archiving them fails. What was done is
+    /// recorded in `stats`, even if purging the slots fails. Called again
+    /// with the same `stats` after a failure, it resumes after the last purge
+    /// step that completed, without archiving the slots again.
+    fn purge_scanned_slots(
+        blockstore: &Blockstore,
+        root: Slot,
+        slots_to_clean: SlotsToClean,
+        config: &BlockstoreCleanupConfig,
+        exit: &AtomicBool,
+        stats: &mut CleanupStats,
+    ) -> BlockstoreResult<()> {
+        let SlotsToClean {
+            should_clean: slots_to_clean,
+            lowest_cleanup_slot,
+            total_shreds,
+            total_slots,
+            limit,
+            ..
+        } = slots_to_clean;
+
+        // Only the slots below the root are cleaned, whatever the limit: the
+        // slots above the root replay from it. limit_cleanup_slot() keeps
+        // the built-in limits below it, but a custom policy can select any
+        // slot.
+        if slots_to_clean && lowest_cleanup_slot >= root {
+            error!(
+                "Skipping Blockstore cleanup: lowest cleanup slot {lowest_cleanup_slot} is not \
+                 below the root {root}",
+            );
+            datapoint_error!(
+                "ledger_cleanup_invariant_violation",
+                ("lowest_cleanup_slot", lowest_cleanup_slot, i64),
+                ("root", root, i64),
+                (
+                    "limit",
+                    limit.map_or("none", |limit| limit.as_str()),
+                    String
+                ),
+            );
+            return Ok(());
+        }
+
+        if slots_to_clean && config.dry_run {
+            let lowest_slot = Self::lowest_slot(blockstore, config)?;
+            let num_slots = (lowest_cleanup_slot + 1).saturating_sub(lowest_slot);
+            info!(
+                "ledger_cleanup event=dry_run from={lowest_slot} to={lowest_cleanup_slot} \
+                 slots={num_slots}",
+            );
+            datapoint_info!(
+                "ledger_cleanup_dry_run",
+                ("lowest_cleanup_slot", lowest_cleanup_slot, i64),
+                ("num_slots", num_slots, i64),
+                ("total_shreds", total_shreds, i64),
+            );
+            stats.lowest_cleanup_slot = lowest_cleanup_slot;
+        } else if slots_to_clean
+            && matches!(&config.purge_veto, Some(purge_veto) if !purge_veto(lowest_cleanup_slot))
+        {
+            info!("ledger_cleanup event=veto to={lowest_cleanup_slot} reason=purge_veto");
+            datapoint_info!("ledger_cleanup_purge_vetoed", ("count", 1, i64));
+            stats.purge_vetoed = true;
+        } else if slots_to_clean
+            && matches!(
+                &config.snapshot_in_progress,
+                Some(snapshot_in_progress) if snapshot_in_progress.load(Ordering::Acquire)
+            )
+        {
+            info!("ledger_cleanup event=veto to={lowest_cleanup_slot} reason=snapshot");
+            datapoint_info!("ledger_cleanup_deferred_for_snapshot", ("count", 1, i64));
+            stats.purge_vetoed = true;
+        } else if slots_to_clean {
+            let lowest_slot = Self::lowest_slot(blockstore, config)?;
+
+            if lowest_cleanup_slot < lowest_slot {
+                // An earlier cycle already purged through this cutoff, e.g. a
+                // clamp held it in place while the budget is still exceeded.
+                // A failed purge leaves its slots behind, so it is not skipped.
+                debug!(
+                    "ledger_cleanup event=noop to={lowest_cleanup_slot} \
+                     lowest_slot={lowest_slot}"
+                );
+                datapoint_info!("ledger_cleanup_noop", ("count", 1, i64));
+                stats.noop = true;
+            } else if stats.archived
+                || Self::archive_slots(blockstore, lowest_slot, lowest_cleanup_slot, config)
+            {
+                stats.archived = config.archiver.is_some();
+                let purge_disk_utilization_pre = config
+                    .report_disk_metrics
+                    .then(|| blockstore.storage_size());
+                let mut purge_time = Measure::start("purge_slots()");
+                // purge any slots older than lowest_cleanup_slot. Nothing
+                // below lowest_existing_slot is left to purge.
+                let from_slot = config
+                    .lowest_existing_slot
+                    .load(Ordering::Relaxed)
+                    .min(lowest_slot);
+                let result = Self::purge_slots(
+                    blockstore,
+                    from_slot,
+                    lowest_slot,
+                    lowest_cleanup_slot,
+                    config,
+                    exit,
+                    stats,
+                );
+                purge_time.stop();
+                stats.purge_duration += purge_time.as_duration();
+                // Also after a failure, so that a retry starts from the
+                // first slot that is left
+                Self::update_lowest_existing_slot(blockstore, config);
+                let lowest_cleanup_slot = result?;
+
+                if let Some(Ok(pre)) = purge_disk_utilization_pre {
+                    if let Ok(post) = blockstore.storage_size() {
+                        stats.purge_disk_utilization_delta = Some(pre as i64 - post as i64);
+                    }
+                }
+
+                if let Some(on_purge) = &config.on_purge {
+                    on_purge(from_slot, lowest_cleanup_slot);
+                }
+
+                let shreds_per_slot = total_shreds.checked_div(total_slots).unwrap_or(0);
+                stats.shreds_purged = stats.slots_purged.saturating_mul(shreds_per_slot);
+                info!(
+                    "ledger_cleanup event=purge from={from_slot} to={lowest_cleanup_slot} \
+                     slots={} shreds={} reason={} purge_us={}",
+                    stats.slots_purged,
+                    stats.shreds_purged,
+                    limit.map_or("none", |limit| limit.as_str()),
+                    stats.purge_duration.as_micros(),
+                );
+
+                if let Some(sender) = &config.purge_event_sender {
+                    let event = PurgeEvent {
+                        from: lowest_slot,
+                        to: lowest_cleanup_slot,
+                        shreds: stats.shreds_purged,
+                        at: Instant::now(),
+                    };
+                    match sender.try_send(event) {
+                        Ok(()) => (),
+                        Err(TrySendError::Full(_)) => {
+                            warn!("Dropping purge event, channel is full: {event:?}");
+                            stats.purge_event_dropped = true;
+                        }
+                        Err(TrySendError::Disconnected(_)) => {
+                            debug!("Dropping purge event, channel is disconnected: {event:?}");
+                        }
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Hands \[`from_slot`, `to_slot`\] to the configured archiver, if any.
+    ///
+    /// Returns whether the slots may be purged; they must not be if archiving
+    /// them failed.
+    fn archive_slots(
+        blockstore: &Blockstore,
+        from_slot: Slot,
+        to_slot: Slot,
+        config: &BlockstoreCleanupConfig,
+    ) -> bool {
+        let Some(archiver) = &config.archiver else {
+            return true;
+        };
+        if from_slot > to_slot {
+            return true;
+        }
+        let mut archive_time = Measure::start("archive()");
+        let result = archiver.archive(blockstore, from_slot, to_slot);
+        archive_time.stop();
+        match result {
+            Ok(num_bytes) => {
+                datapoint_info!(
+                    "ledger_cleanup_archive",
+                    ("num_slots", to_slot - from_slot + 1, i64),
+                    ("num_bytes", num_bytes, i64),
+                    ("archive_us", archive_time.as_us(), i64),
+                );
+                true
+            }
+            Err(err) => {
+                error!(
+                    "Skipping Blockstore cleanup: failed to archive slots [{from_slot}, \
+                     {to_slot}]: {err:?}",
+                );
+                datapoint_error!(
+                    "ledger_cleanup_archive_error",
+                    ("from_slot", from_slot, i64),
+                    ("to_slot", to_slot, i64),
+                    ("error", format!("{err:?}"), String),
+                );
+                false
+            }
+        }
+    }
+
+    /// Purges \[`from_slot`, `to_slot`\] in steps of at most
+    /// `config.max_slots_per_purge` slots, starting from the oldest. Slots
+    /// below `lowest_slot` hold no data, so they don't count towards a step.
+    ///
+    /// Returns the highest slot that was purged, which is lower than
+    /// `to_slot` if `exit` was set before all of the steps completed. If a
+    /// step fails, the lowest cleanup slot and the max expired slot are left
+    /// where the previous step put them and the error is returned. Each
+    /// completed step is added to `stats`.
+    fn purge_slots(
+        blockstore: &Blockstore,
+        from_slot: Slot,
+        lowest_slot: Slot,
+        to_slot: Slot,
+        config: &BlockstoreCleanupConfig,
+        exit: &AtomicBool,
+        stats: &mut CleanupStats,
+    ) -> BlockstoreResult<Slot> {
+        let max_slots_per_purge = config.max_slots_per_purge.unwrap_or(u64::MAX).max(1);
+        let max_purge_rate = config
+            .max_purge_bytes_per_sec
+            .and_then(|max_bytes_per_sec| {
+                Some((
+                    max_bytes_per_sec.max(1),
+                    Self::mean_bytes_per_slot(blockstore, lowest_slot)?,
+                ))
+            });
+        // See BlockstoreCleanupConfig::metadata_retention_slots
+        let purge_type = if config.metadata_retention_slots.is_some() {
+            PurgeType::CompactionFilter
+        } else {
+            config.purge_type
+        };
+        let first_slot = std::cmp::max(from_slot, lowest_slot);
+        let num_slots = (to_slot + 1).saturating_sub(first_slot).max(1);
+        config.purge_progress.store(0, Ordering::Relaxed);
+        let mut start_slot = from_slot;
+        loop {
+            let end_slot = std::cmp::max(start_slot, lowest_slot)
+                .saturating_add(max_slots_per_purge - 1)
+                .min(to_slot);
+            let step_start = config.clock.now();
+
+            let mut slot_update_time = Measure::start("lowest_cleanup_slot");
+            let prev_lowest_cleanup_slot = std::mem::replace(
+                &mut *blockstore.lowest_cleanup_slot.write().unwrap(),
+                end_slot,
+            );
+            slot_update_time.stop();
+            stats.slot_update_duration += slot_update_time.as_duration();
+
+            if let Err(err) = blockstore.try_purge_slots(start_slot, end_slot, purge_type) {
+                // The range is retried by a later cleanup; until then, the
+                // lowest cleanup slot must not claim it is gone
+                *blockstore.lowest_cleanup_slot.write().unwrap() = prev_lowest_cleanup_slot;
+                return Err(err);
+            }
+            // Update only after purge operation.
+            // Safety: This value can be used by compaction_filters shared via Arc<AtomicU64>.
+            // Compactions are async and run as a multi-threaded background job. However, this
+            // shouldn't cause consistency issues for iterators and getters because we have
+            // already expired all affected keys (older than or equal to end_slot)
+            // by the above `purge_slots`. According to the general RocksDB design where SST
+            // files are immutable, even running iterators aren't affected; the database grabs
+            // a snapshot of the live set of sst files at iterator's creation.
+            // Also, with the default PurgeType::CompactionFilter, there is no delete_range for
+            // transaction_status and address_signatures CFs. These are fine because they
+            // don't require strong consistent view for their operation.
+            let mut slot_update_time = Measure::start("set_max_expired_slot()");
+            match config.metadata_retention_slots {
+                None => blockstore.set_max_expired_slot(end_slot),
+                Some(metadata_retention_slots) => {
+                    // Only the metadata columns rely on the compaction
+                    // filter; the rest were range-deleted above
+                    if let Some(metadata_cutoff) = end_slot.checked_sub(metadata_retention_slots) {
+                        blockstore.set_max_expired_slot(metadata_cutoff);
+                    }
+                }
+            }
+            slot_update_time.stop();
+            stats.slot_update_duration += slot_update_time.as_duration();
+            stats.purged = true;
+            stats.lowest_cleanup_slot = end_slot;
+            stats.slots_purged +=
+                (end_slot + 1).saturating_sub(std::cmp::max(start_slot, lowest_slot));
+
+            if config.compact_after_purge {
+                let mut compaction_time = Measure::start("compact_slots()");
+                blockstore.compact_slots(
+                    std::cmp::max(start_slot, lowest_slot),
+                    end_slot,
+                    &config.compaction_columns,
+                );
+                compaction_time.stop();
+                info!("Compacted purged Blockstore slots through {end_slot}. {compaction_time}");
+                stats.compaction_duration += compaction_time.as_duration();
+            }
+
+            let num_slots_purged = (end_slot + 1).saturating_sub(first_slot);
+            let progress = (num_slots_purged.saturating_mul(100) / num_slots).min(100);
+            let prev_progress = config.purge_progress.swap(progress, Ordering::Relaxed);
+            if end_slot < to_slot {
+                if let Some(milestone) = [75, 50, 25]
+                    .into_iter()
+                    .find(|milestone| prev_progress < *milestone && *milestone <= progress)
+                {
+                    info!(
+                        "Blockstore cleanup is {milestone}% done, purged through slot \
+                         {end_slot} of {to_slot}"
+                    );
+                }
+            }
+
+            if end_slot >= to_slot {
+                return Ok(end_slot);
+            }
+            if exit.load(Ordering::Relaxed) {
+                info!("Stopping Blockstore cleanup at slot {end_slot} of {to_slot} due to exit");
+                return Ok(end_slot);
+            }
+            info!(
+                "Cleaned up Blockstore data through slot {end_slot}, {} slots remaining",
+                to_slot - end_slot,
+            );
+
+            if let Some((max_bytes_per_sec, mean_bytes_per_slot)) = max_purge_rate {
+                let num_slots =
+                    (end_slot + 1).saturating_sub(std::cmp::max(start_slot, lowest_slot));
+                let num_bytes = num_slots.saturating_mul(mean_bytes_per_slot);
+                let step_duration =
+                    Duration::from_secs_f64(num_bytes as f64 / max_bytes_per_sec as f64);
+                let mut remaining =
+                    step_duration.saturating_sub(config.clock.now().duration_since(step_start));
+                while !remaining.is_zero() {
+                    let wait = if config.exit_poll_interval.is_zero() {
+                        remaining
+                    } else {
+                        remaining.min(config.exit_poll_interval)
+                    };
+                    config.clock.sleep(wait);
+                    stats.throttle_duration += wait;
+                    remaining -= wait;
+                    if exit.load(Ordering::Relaxed) {
+                        info!(
+                            "Stopping Blockstore cleanup at slot {end_slot} of {to_slot} due to \
+                             exit"
+                        );
+                        return Ok(end_slot);
+                    }
+                }
+            }
+            start_slot = end_slot + 1;
+        }
+    }
+
+    /// Returns the number of slots in the blockstore with up to 100, 1,000,
+    /// 5,000 and more shreds, along with the number of slots that are not
+    /// full. The shreds received for an incomplete slot may have holes, so
+    /// these slots account for some of the difference between the shred
+    /// counts and the actual disk usage.
+    fn shred_histogram(blockstore: &Blockstore) -> BlockstoreResult<([u64; 4], u64)> {
+        let mut histogram = [0; 4];
+        let mut num_incomplete_slots = 0;
+        for (_, meta) in blockstore.slot_meta_iterator(blockstore.lowest_cleanup_slot())? {
+            let bucket = match meta.received {
+                0..=100 => 0,
+                101..=1_000 => 1,
+                1_001..=5_000 => 2,
+                _ => 3,
+            };
+            histogram[bucket] += 1;
+            if !meta.is_full() {
+                num_incomplete_slots += 1;
+            }
+        }
+        Ok((histogram, num_incomplete_slots))
+    }
+
+    fn report_shred_histogram(blockstore: &Blockstore) {
+        let mut histogram_time = Measure::start("shred_histogram()");
+        let (histogram, num_incomplete_slots) = match Self::shred_histogram(blockstore) {
+            Ok(histogram) => histogram,
+            Err(err) => {
+                warn!("Unable to compute the Blockstore shred histogram: {err:?}");
+                return;
+            }
+        };
+        histogram_time.stop();
+        datapoint_info!(
+            "ledger_cleanup_shred_histogram",
+            ("slots_0_100", histogram[0], i64),
+            ("slots_100_1k", histogram[1], i64),
+            ("slots_1k_5k", histogram[2], i64),
+            ("slots_5k_plus", histogram[3], i64),
+            ("incomplete_slots", num_incomplete_slots, i64),
+            ("histogram_us", histogram_time.as_us(), i64),
+        );
+    }
+
+    /// Returns the mean on-disk size of a data shred in the last few full
+    /// slots below the root, counting the coding shreds that accompany
+    /// them, or `None` if there are no such slots.
+    fn sample_bytes_per_shred(blockstore: &Blockstore) -> BlockstoreResult<Option<u64>> {
+        let max_root = blockstore.max_root();
+        let start_slot = max_root.saturating_sub(SHRED_SIZE_SAMPLE_WINDOW_SLOTS);
+        let mut slots = Vec::new();
+        for (slot, meta) in blockstore.slot_meta_iterator(start_slot)? {
+            if slot > max_root {
+                break;
+            }
+            if slot > 0 && meta.is_full() {
+                slots.push((slot, meta.consumed));
+            }
+        }
+
+        let mut num_bytes: u64 = 0;
+        let mut num_shreds: u64 = 0;
+        for (slot, consumed) in slots.into_iter().rev().take(SHRED_SIZE_SAMPLE_SLOTS) {
+            for (_, payload) in blockstore.slot_data_iterator(slot, 0)? {
+                num_bytes += payload.len() as u64;
+            }
+            for (_, payload) in blockstore.slot_coding_iterator(slot, 0)? {
+                num_bytes += payload.len() as u64;
+            }
+            num_shreds += consumed;
+        }
+        Ok(num_bytes.checked_div(num_shreds))
+    }
+
+    fn calibrate_shred_size(blockstore: &Blockstore, config: &BlockstoreCleanupConfig) {
+        let mut calibration_time = Measure::start("calibrate_shred_size()");
+        let sampled_bytes_per_shred = match Self::sample_bytes_per_shred(blockstore) {
+            Ok(Some(sampled_bytes_per_shred)) => sampled_bytes_per_shred,
+            Ok(None) => {
+                debug!("Skipping shred size calibration: no full slots below the root");
+                return;
+            }
+            Err(err) => {
+                warn!("Unable to sample the Blockstore shred size: {err:?}");
+                return;
+            }
+        };
+        let bytes_per_shred = config.shred_size_estimator.as_ref().map(|estimator| {
+            estimator.calibrate(sampled_bytes_per_shred);
+            estimator.avg_bytes_per_shred(blockstore)
+        });
+        calibration_time.stop();
+        datapoint_info!(
+            "ledger_cleanup_bytes_per_shred",
+            ("sampled_bytes_per_shred", sampled_bytes_per_shred, i64),
+            ("bytes_per_shred", bytes_per_shred, Option<i64>),
+            ("calibration_us", calibration_time.as_us(), i64),
+        );
+    }
+
+    /// Returns the number of bytes that `num_shreds` live data shreds are
+    /// estimated to occupy, using `config.shred_size_estimator` or else
+    /// `DEFAULT_BYTES_PER_SHRED`, along with the ratio of that estimate to
+    /// `actual_bytes`.
+    fn estimated_bytes(
+        blockstore: &Blockstore,
+        config: &BlockstoreCleanupConfig,
+        num_shreds: u64,
+        actual_bytes: u64,
+    ) -> (u64, Option<f64>) {
+        let bytes_per_shred = config
+            .shred_size_estimator
+            .as_ref()
+            .map(|estimator| estimator.avg_bytes_per_shred(blockstore))
+            .unwrap_or(DEFAULT_BYTES_PER_SHRED);
+        let estimated_bytes = num_shreds.saturating_mul(bytes_per_shred);
+        let ratio = (actual_bytes > 0).then(|| estimated_bytes as f64 / actual_bytes as f64);
+        (estimated_bytes, ratio)
+    }
+
+    /// Reports how closely the shred count tracks the blockstore's actual
+    /// size. A ratio that drifts away from 1.0 means that `max_ledger_shreds`
+    /// no longer maps to the disk usage it was sized for.
+    fn report_estimated_bytes(
+        blockstore: &Blockstore,
+        config: &BlockstoreCleanupConfig,
+        num_shreds: u64,
+        actual_bytes: u64,
+    ) {
+        let (estimated_bytes, ratio) =
+            Self::estimated_bytes(blockstore, config, num_shreds, actual_bytes);
+        datapoint_info!(
+            "ledger_cleanup_estimated_bytes",
+            ("total_shreds", num_shreds, i64),
+            ("estimated_bytes", estimated_bytes, i64),
+            ("actual_bytes", actual_bytes, i64),
+            ("estimated_to_actual_ratio", ratio, Option<f64>),
+        );
+    }
+
+    /// Reports `num_shreds` if it exceeds `config.warn_threshold_shreds` but
+    /// not `config.max_ledger_shreds`, and returns whether it did.
+    fn report_approaching_limit(num_shreds: u64, config: &BlockstoreCleanupConfig) -> bool {
+        let Some(warn_threshold_shreds) = config.warn_threshold_shreds else {
+            return false;
+        };
+        if num_shreds <= warn_threshold_shreds || num_shreds > config.max_ledger_shreds {
+            return false;
+        }
+        warn!(
+            "Blockstore has {num_shreds} alive shreds, above the warning threshold of \
+             {warn_threshold_shreds} and approaching the maximum of {}",
+            config.max_ledger_shreds,
+        );
+        datapoint_warn!(
+            "ledger_cleanup_approaching_limit",
+            ("total_shreds", num_shreds, i64),
+            ("warn_threshold_shreds", warn_threshold_shreds, i64),
+            ("max_ledger_shreds", config.max_ledger_shreds, i64),
+        );
+        true
+    }
+
+    fn report_over_budget(
+        remaining_shreds: u64,
+        max_ledger_shreds: u64,
+        lowest_cleanup_slot: Slot,
+        limited_cleanup_slot: Option<Slot>,
+    ) {
+        let overage = remaining_shreds - max_ledger_shreds;
+        warn!(
+            "Blockstore cleanup was limited to slot {limited_cleanup_slot:?} instead of \
+             {lowest_cleanup_slot}; an estimated {remaining_shreds} alive shreds remain, \
+             {overage} over the maximum of {max_ledger_shreds}",
+        );
+        datapoint_warn!(
+            "ledger_cleanup_over_budget",
+            ("overage_shreds", overage, i64),
+            ("remaining_shreds", remaining_shreds, i64),
+            ("max_ledger_shreds", max_ledger_shreds, i64),
+            ("lowest_cleanup_slot", lowest_cleanup_slot, i64),
+            (
+                "limited_cleanup_slot",
+                limited_cleanup_slot.map(|slot| slot as i64),
+                Option<i64>
+            ),
+        );
+    }
+
+    /// Returns the last purge slot saved by a previous run of the service,
+    /// or 0 if there is none, it is unusable or `persist_last_purge_slot` is
+    /// not set.
+    fn load_last_purge_slot(blockstore: &Blockstore, config: &BlockstoreCleanupConfig) -> Slot {
+        if !config.persist_last_purge_slot {
+            return 0;
+        }
+        let path = blockstore.ledger_path().join(LAST_PURGE_SLOT_FILE);
+        let last_purge_slot = match fs::read_to_string(&path) {
+            Ok(contents) => match contents.trim().parse::<Slot>() {
+                Ok(last_purge_slot) => last_purge_slot,
+                Err(err) => {
+                    warn!("Ignoring invalid last purge slot in {path:?}: {err}");
+                    return 0;
+                }
+            },
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return 0,
+            Err(err) => {
+                warn!("Unable to read the last purge slot from {path:?}: {err}");
+                return 0;
+            }
+        };
+        // The blockstore may have been replaced since, in which case the
+        // saved slot would hold off cleanups until the root caught up to it
+        let root = blockstore.max_root();
+        if last_purge_slot > root {
+            warn!("Ignoring last purge slot {last_purge_slot} above the root {root}");
+            return 0;
+        }
+        info!("Resuming Blockstore cleanup from last purge slot {last_purge_slot}");
+        last_purge_slot
+    }
+
+    /// Saves `last_purge_slot` for the next run of the service if
+    /// `persist_last_purge_slot` is set.
+    fn store_last_purge_slot(
+        blockstore: &Blockstore,
+        config: &BlockstoreCleanupConfig,
+        last_purge_slot: Slot,
+    ) {
+        if !config.persist_last_purge_slot {
+            return;
+        }
+        let path = blockstore.ledger_path().join(LAST_PURGE_SLOT_FILE);
+        // Write to a temporary file and rename it so that a crash can't
+        // leave a partially written value behind
+        let tmp_path = path.with_extension("tmp");
+        if let Err(err) = fs::write(&tmp_path, last_purge_slot.to_string())
+            .and_then(|()| fs::rename(&tmp_path, &path))
+        {
+            warn!("Unable to save the last purge slot to {path:?}: {err}");
+        }
+    }
+
+    /// Returns how long it takes for `remaining_shreds` live data shreds to
+    /// reach `max_ledger_shreds` when growing by `growth_per_slot` shreds per
+    /// slot, or `None` if the blockstore is not growing.
+    fn projected_time_to_full(
+        max_ledger_shreds: u64,
+        remaining_shreds: u64,
+        growth_per_slot: u64,
+        ms_per_slot: u64,
+    ) -> Option<Duration> {
+        let num_slots = max_ledger_shreds
+            .saturating_sub(remaining_shreds)
+            .checked_div(growth_per_slot)?;
+        Some(Duration::from_millis(num_slots.saturating_mul(ms_per_slot)))
+    }
+
+    /// Returns whether `min_scan_interval` has elapsed since the last scan
+    /// of the blockstore, at `last_scan_time`, or the estimated number of
+    /// live data shreds calls for a cleanup regardless.
+    fn min_scan_interval_elapsed(
+        last_scan_time: Option<Instant>,
+        blockstore: &Blockstore,
+        config: &BlockstoreCleanupConfig,
+    ) -> bool {
+        let Some(last_scan_time) = last_scan_time else {
+            return true;
+        };
+        let elapsed = config.clock.now().duration_since(last_scan_time);
+        if elapsed >= config.min_scan_interval {
+            return true;
+        }
+        let over_budget = config
+            .shred_count_estimator
+            .as_ref()
+            .and_then(|estimator| estimator.estimated_num_shreds(blockstore))
+            .is_some_and(|num_shreds| num_shreds > config.max_ledger_shreds);
+        if over_budget {
+            return true;
+        }
+        debug!(
+            "Skipping Blockstore cleanup: {elapsed:?} since the last scan is within the minimum \
+             interval of {:?}",
+            config.min_scan_interval,
+        );
+        false
+    }
+
+    /// Returns whether `min_wall_interval` has elapsed since the last
+    /// cleanup, which ran at `last_purge_time`.
+    fn min_wall_interval_elapsed(
+        last_purge_time: Option<Instant>,
+        config: &BlockstoreCleanupConfig,
+    ) -> bool {
+        let Some(last_purge_time) = last_purge_time else {
+            return true;
+        };
+        let elapsed = config.clock.now().duration_since(last_purge_time);
+        if elapsed < config.min_wall_interval {
+            debug!(
+                "Skipping Blockstore cleanup: {elapsed:?} since the last cleanup is within the \
+                 minimum interval of {:?}",
+                config.min_wall_interval,
+            );
+            return false;
+        }
+        true
+    }
+
+    fn report_idle(root: Slot, idle_duration: Duration, idle_threshold: Duration) {
+        if idle_duration > idle_threshold {
+            warn!(
+                "Blockstore cleanup has not seen a new root since {root} for {}s",
+                idle_duration.as_secs(),
+            );
+            datapoint_warn!(
+                "ledger_cleanup_idle",
+                ("root", root, i64),
+                ("idle_secs", idle_duration.as_secs(), i64),
+            );
+        }
+    }
+
+    /// Returns the free space on the ledger volume if `min_free_disk_bytes`
+    /// is set, so that it can be reported alongside the disk utilization.
+    fn free_disk_space(blockstore: &Blockstore, config: &BlockstoreCleanupConfig) -> Option<u64> {
+        config.min_free_disk_bytes?;
+        available_disk_space(blockstore.ledger_path()).ok()
+    }
+
+    fn ledger_volume_capacity(
+        blockstore: &Blockstore,
+        config: &BlockstoreCleanupConfig,
+    ) -> Option<u64> {
+        config
+            .ledger_volume_capacity_bytes
+            .or_else(|| disk_capacity(blockstore.ledger_path()).ok())
+    }
+
+    /// Reports the blockstore size and free disk space after a cleanup
+    /// against `pre` and `free_pre`, their values before it.
+    fn report_disk_metrics(
+        blockstore: &Blockstore,
+        config: &BlockstoreCleanupConfig,
+        pre: BlockstoreResult<u64>,
+        free_pre: Option<u64>,
+        stats: &CleanupStats,
+    ) {
+        let post = blockstore.storage_size();
+        let free_post = Self::free_disk_space(blockstore, config);
+        if let (Ok(pre), Ok(post)) = (pre, post) {
+            let utilization_pct = Self::ledger_volume_capacity(blockstore, config)
+                .filter(|capacity| *capacity > 0)
+                .map(|capacity| post as f64 * 100.0 / capacity as f64);
+            datapoint_info!(
+                "ledger_disk_utilization",
+                ("disk_utilization_pre", pre as i64, i64),
+                ("disk_utilization_post", post as i64, i64),
+                ("disk_utilization_delta", (pre as i64 - post as i64), i64),
+                (
+                    "purge_disk_utilization_delta",
+                    stats.purge_disk_utilization_delta,
+                    Option<i64>
+                ),
+                ("total_shreds", stats.total_shreds, i64),
+                ("total_slots", stats.total_slots, i64),
+                ("limit", stats.limit.map(|limit| limit.as_str()), Option<String>),
+                ("free_disk_space_pre", free_pre, Option<i64>),
+                ("free_disk_space_post", free_post, Option<i64>),
+                ("disk_utilization_pct", utilization_pct, Option<f64>),
+            );
+        }
+    }
+
+    /// Reports the post-cleanup size of the columns that grow with the
+    /// number of slots retained, to tell whether the shreds or the
+    /// transaction history dominate the disk usage.
+    fn report_column_disk_metrics(blockstore: &Blockstore) {
+        let column_sizes = match blockstore.column_storage_sizes() {
+            Ok(column_sizes) => column_sizes,
+            Err(err) => {
+                warn!("Unable to determine Blockstore column sizes: {err:?}");
+                return;
+            }
+        };
+        let size = |column: &str| column_sizes.get(column).copied().unwrap_or(0);
+        datapoint_info!(
+            "ledger_disk_utilization_by_column",
+            (cf::ShredData::NAME, size(cf::ShredData::NAME), i64),
+            (cf::ShredCode::NAME, size(cf::ShredCode::NAME), i64),
+            (
+                cf::TransactionStatus::NAME,
+                size(cf::TransactionStatus::NAME),
+                i64
+            ),
+            (
+                cf::AddressSignatures::NAME,
+                size(cf::AddressSignatures::NAME),
+                i64
+            ),
+            (
+                cf::TransactionMemos::NAME,
+                size(cf::TransactionMemos::NAME),
+                i64
+            ),
+            (cf::Rewards::NAME, size(cf::Rewards::NAME), i64),
+            ("total", column_sizes.values().sum::<u64>(), i64),
+        );
+    }
+
+    /// Waits for the service to stop. Purges run on the cleanup thread, so
+    /// an in-flight purge is finished (or, if it is split into several steps,
+    /// stopped at the next step) before this returns.
+    pub fn join(self) -> thread::Result<()> {
+        self.t_cleanup.join()
+    }
+
+    /// Like `join()`, but gives up waiting after `timeout` so that a wedged
+    /// purge cannot hold up shutdown indefinitely. The cleanup thread is then
+    /// left running detached, and `Ok` is returned.
+    pub fn join_timeout(self, timeout: Duration) -> thread::Result<()> {
+        let start = Instant::now();
+        while !self.t_cleanup.is_finished() {
+            let elapsed = start.elapsed();
+            if elapsed >= timeout {
+                warn!(
+                    "BlockstoreCleanupService did not stop within {timeout:?}, leaving its \
+                     thread detached"
+                );
+                datapoint_warn!("ledger_cleanup_join_timeout", ("count", 1, i64));
+                return Ok(());
+            }
+            thread::sleep(JOIN_POLL_INTERVAL.min(timeout - elapsed));
+        }
+        self.t_cleanup.join()
+    }
+}
+#[cfg(test)]
+mod tests {
+    use {
+        super::*,
+        crate::{
+            blockstore::make_many_slot_entries,
+            blockstore_options::{AccessType, BlockstoreOptions},
+            get_tmp_ledger_path_auto_delete,
+        },
+        solana_sdk::{pubkey::Pubkey, signature::Signature},
+        solana_transaction_status::TransactionStatusMeta,
+        std::path::Path,
+    };
+
+    fn flush_blockstore_contents_to_disk(blockstore: Blockstore) -> Blockstore {
+        // The find_slots_to_clean() routine uses a method that queries data
+        // from RocksDB SST files. On a running validator, these are created
+        // fairly regularly as new data is coming in and contents of memory are
+        // pushed to disk. In a unit test environment, we aren't pushing nearly
+        // enough data for this to happen organically. So, instead open and
+        // close the Blockstore which will perform the flush to SSTs.
+        let ledger_path = blockstore.ledger_path().clone();
+        drop(blockstore);
+        Blockstore::open(&ledger_path).unwrap()
+    }
+
+    fn setup_blockstore_with_slots(ledger_path: &Path, num_slots: u64) -> Arc<Blockstore> {
+        let blockstore = Blockstore::open(ledger_path).unwrap();
+        let (shreds, _) = make_many_slot_entries(1, num_slots, 200);
+        blockstore.insert_shreds(shreds, None, false).unwrap();
+        Arc::new(flush_blockstore_contents_to_disk(blockstore))
+    }
+
+    fn config_with_max_ledger_shreds(max_ledger_shreds: u64) -> BlockstoreCleanupConfig {
+        BlockstoreCleanupConfig {
+            max_ledger_shreds,
+            hysteresis: 0.0,
+            repair_retention_slots: 0,
+            ..BlockstoreCleanupConfig::default()
+        }
+    }
+
+    #[test]
+    fn test_find_slots_to_clean() {
+        // BlockstoreCleanupService::find_slots_to_clean() does not modify the
+        // Blockstore, so we can make repeated calls on the same slots
+        solana_logger::setup();
+        let ledger_path = get_tmp_ledger_path_auto_delete!();
+        let blockstore = Blockstore::open(ledger_path.path()).unwrap();
+
+        // Construct and build some shreds for slots [1, 10]
+        let num_slots: u64 = 10;
+        let num_entries = 200;
+        let (shreds, _) = make_many_slot_entries(1, num_slots, num_entries);
+        let shreds_per_slot = (shreds.len() / num_slots as usize) as u64;
+        assert!(shreds_per_slot > 1);
+        blockstore.insert_shreds(shreds, None, false).unwrap();
+
+        // Initiate a flush so inserted shreds found by find_slots_to_clean()
+        let blockstore = Arc::new(flush_blockstore_contents_to_disk(blockstore));
+
+        // Ensure no cleaning of slots >= last_root
+        let last_root = 0;
+        let max_ledger_shreds = 0;
+        let SlotsToClean { should_clean, .. } = BlockstoreCleanupService::find_slots_to_clean(
+            &blockstore,
+            last_root,
+            &config_with_max_ledger_shreds(max_ledger_shreds),
+        );
+        assert!(!should_clean);
+        // Slot 0 will exist in blockstore with zero shreds since it is slot
+        // 1's parent. Thus, slot 0 will be identified for clean once it is
+        // below the root.
+        let last_root = 1;
+        let SlotsToClean {
+            should_clean,
+            lowest_cleanup_slot: lowest_purged,
+            ..
+        } = BlockstoreCleanupService::find_slots_to_clean(
+            &blockstore,
+            last_root,
+            &config_with_max_ledger_shreds(max_ledger_shreds),
+        );
+        assert!(should_clean && lowest_purged == 0);
+        // Now, set max_ledger_shreds to 1, slot 0 still eligible for clean
+        let max_ledger_shreds = 1;
+        let SlotsToClean {
+            should_clean,
+            lowest_cleanup_slot: lowest_purged,
+            ..
+        } = BlockstoreCleanupService::find_slots_to_clean(
+            &blockstore,
+            last_root,
+            &config_with_max_ledger_shreds(max_ledger_shreds),
+        );
+        assert!(should_clean && lowest_purged == 0);
+
+        // Ensure no cleaning if blockstore contains fewer than max_ledger_shreds
+        let last_root = num_slots;
+        let max_ledger_shreds = (shreds_per_slot * num_slots) + 1;
+        let SlotsToClean {
+            should_clean,
+            lowest_cleanup_slot: lowest_purged,
+            ..
+        } = BlockstoreCleanupService::find_slots_to_clean(
+            &blockstore,
+            last_root,
+            &config_with_max_ledger_shreds(max_ledger_shreds),
+        );
+        assert!(!should_clean && lowest_purged == 0);
+
+        for slot in 1..=num_slots {
+            // Set last_root to make slots <= slot eligible for cleaning
+            let last_root = slot + 1;
+            // Set max_ledger_shreds to 0 so that all eligible slots are cleaned
+            let max_ledger_shreds = 0;
+            let SlotsToClean {
+                should_clean,
+                lowest_cleanup_slot: lowest_purged,
+                ..
+            } = BlockstoreCleanupService::find_slots_to_clean(
+                &blockstore,
+                last_root,
+                &config_with_max_ledger_shreds(max_ledger_shreds),
+            );
+            assert!(should_clean && lowest_purged == slot);
+
+            // Set last_root to make all slots eligible for cleaning
+            let last_root = num_slots + 1;
+            // Set max_ledger_shreds to the number of shreds in slots > slot.
+            // This will make it so that slots [1, slot] are cleaned
+            let max_ledger_shreds = shreds_per_slot * (num_slots - slot);
+            let SlotsToClean {
+                should_clean,
+                lowest_cleanup_slot: lowest_purged,
+                ..
+            } = BlockstoreCleanupService::find_slots_to_clean(
+                &blockstore,
+                last_root,
+                &config_with_max_ledger_shreds(max_ledger_shreds),
+            );
+            assert!(should_clean && lowest_purged == slot);
+        }
+    }
+
+    #[test]
+    fn test_estimated_bytes() {
+        let ledger_path = get_tmp_ledger_path_auto_delete!();
+        let blockstore = Blockstore::open(ledger_path.path()).unwrap();
+        let mut config = BlockstoreCleanupConfig::default();
+        assert_eq!(
+            BlockstoreCleanupService::estimated_bytes(&blockstore, &config, 100, 400_000),
+            (100 * DEFAULT_BYTES_PER_SHRED, Some(0.5))
+        );
+
+        config.shred_size_estimator = Some(Arc::new(ConstantShredSizeEstimator(4_000)));
+        assert_eq!(
+            BlockstoreCleanupService::estimated_bytes(&blockstore, &config, 100, 400_000),
+            (400_000, Some(1.0))
+        );
+        assert_eq!(
+            BlockstoreCleanupService::estimated_bytes(&blockstore, &config, 100, 0),
+            (400_000, None)
+        );
+    }
+
+    #[test]
+    fn test_report_approaching_limit() {
+        let mut config = config_with_max_ledger_shreds(100);
+        assert!(!BlockstoreCleanupService::report_approaching_limit(
+            90, &config
+        ));
+
+        config.warn_threshold_shreds = Some(80);
+        assert!(!BlockstoreCleanupService::report_approaching_limit(
+            80, &config
+        ));
+        assert!(BlockstoreCleanupService::report_approaching_limit(
+            90, &config
+        ));
+        assert!(BlockstoreCleanupService::report_approaching_limit(
+            100, &config
+        ));
+        // Over the limit, the cleanup reports the purge instead
+        assert!(!BlockstoreCleanupService::report_approaching_limit(
+            101, &config
+        ));
+    }
+
+    #[test]
+    fn test_num_slots_to_clean() {
+        // A truncated mean of 2 shreds per slot would call for 3 slots
+        assert_eq!(BlockstoreCleanupService::num_slots_to_clean(10, 5, 4), 2);
+        // ... or for 7 slots, more than there are
+        assert_eq!(BlockstoreCleanupService::num_slots_to_clean(7, 0, 4), 4);
+        // Fewer shreds than slots would truncate the mean to 0
+        assert_eq!(BlockstoreCleanupService::num_slots_to_clean(3, 1, 10), 7);
+        assert_eq!(BlockstoreCleanupService::num_slots_to_clean(10, 10, 4), 0);
+        assert_eq!(BlockstoreCleanupService::num_slots_to_clean(0, 0, 4), 0);
+        assert_eq!(
+            BlockstoreCleanupService::num_slots_to_clean(u64::MAX, 0, u64::MAX),
+            u64::MAX
+        );
+    }
+
+    #[test]
+    fn test_find_slots_to_clean_hysteresis() {
+        solana_logger::setup();
+        let ledger_path = get_tmp_ledger_path_auto_delete!();
+        let num_slots: u64 = 10;
+        let blockstore = setup_blockstore_with_slots(ledger_path.path(), num_slots);
+        let total_shreds = BlockstoreCleanupService::find_slots_to_clean(
+            &blockstore,
+            num_slots,
+            &config_with_max_ledger_shreds(u64::MAX),
+        )
+        .total_shreds;
+        let shreds_per_slot = total_shreds / num_slots;
+
+        // Just over the limit, a single slot is cleaned without hysteresis
+        let mut config = config_with_max_ledger_shreds(total_shreds - 1);
+        let SlotsToClean {
+            should_clean,
+            lowest_cleanup_slot,
+            ..
+        } = BlockstoreCleanupService::find_slots_to_clean(&blockstore, num_slots, &config);
+        assert!(should_clean);
+        assert_eq!(lowest_cleanup_slot, 1);
+
+        // With hysteresis, enough slots are cleaned to get 50% below the limit
+        config.hysteresis = 0.5;
+        let SlotsToClean {
+            should_clean,
+            lowest_cleanup_slot,
+            ..
+        } = BlockstoreCleanupService::find_slots_to_clean(&blockstore, num_slots, &config);
+        assert!(should_clean);
+        assert_eq!(
+            lowest_cleanup_slot,
+            (total_shreds - (total_shreds - 1) / 2).div_ceil(shreds_per_slot),
+        );
+
+        // Nothing is cleaned until the limit itself is exceeded
+        config.max_ledger_shreds = total_shreds;
+        let SlotsToClean { should_clean, .. } =
+            BlockstoreCleanupService::find_slots_to_clean(&blockstore, num_slots, &config);
+        assert!(!should_clean);
+
+        // Out of range values are clamped
+        config.hysteresis = 2.0;
+        assert_eq!(config.hysteresis(), 0.5);
+        config.hysteresis = -1.0;
+        assert_eq!(config.hysteresis(), 0.0);
+        config.hysteresis = f64::NAN;
+        assert_eq!(config.hysteresis(), 0.0);
+    }
+
+    struct FixedShredCount(Option<u64>);
+
+    impl ShredCountEstimator for FixedShredCount {
+        fn estimated_num_shreds(&self, _blockstore: &Blockstore) -> Option<u64> {
+            self.0
+        }
+    }
+
+    #[test]
+    fn test_find_slots_to_clean_empty_blockstore() {
+        solana_logger::setup();
+        let ledger_path = get_tmp_ledger_path_auto_delete!();
+        let blockstore = Blockstore::open(ledger_path.path()).unwrap();
+
+        // Even with limits that any data would exceed; finding the
+        // blockstore empty still counts as a scan
+        let mut config = config_with_max_ledger_shreds(0);
+        config.max_ledger_bytes = Some(0);
+        config.max_ledger_duration = Some(Duration::ZERO);
+        let slots_to_clean =
+            BlockstoreCleanupService::try_find_slots_to_clean(&blockstore, 0, &config).unwrap();
+        assert_eq!(slots_to_clean, SlotsToClean::nothing(0, 0));
+        assert!(slots_to_clean.scanned);
+    }
+
+    #[test]
+    fn test_find_slots_to_clean_retain_all() {
+        solana_logger::setup();
+        let ledger_path = get_tmp_ledger_path_auto_delete!();
+        let num_slots: u64 = 10;
+        let blockstore = setup_blockstore_with_slots(ledger_path.path(), num_slots);
+
+        // Nothing is scanned, so no shreds are counted, even with retention
+        // limits that would clean everything
+        let mut config = config_with_max_ledger_shreds(0);
+        config.max_ledger_bytes = Some(0);
+        config.retain_all = true;
+        let slots_to_clean =
+            BlockstoreCleanupService::find_slots_to_clean(&blockstore, num_slots, &config);
+        assert!(!slots_to_clean.should_clean);
+        assert!(!slots_to_clean.scanned);
+        assert_eq!(slots_to_clean.total_shreds, 0);
+    }
+
+    #[test]
+    fn test_find_slots_to_clean_shred_count_estimator() {
+        solana_logger::setup();
+        let ledger_path = get_tmp_ledger_path_auto_delete!();
+        let num_slots: u64 = 10;
+        let blockstore = setup_blockstore_with_slots(ledger_path.path(), num_slots);
+
+        // Without an estimate, the scan finds more shreds than the budget
+        let mut config = config_with_max_ledger_shreds(100);
+        assert!(
+            BlockstoreCleanupService::find_slots_to_clean(&blockstore, num_slots, &config)
+                .should_clean
+        );
+        config.shred_count_estimator = Some(Arc::new(FixedShredCount(None)));
+        assert!(
+            BlockstoreCleanupService::find_slots_to_clean(&blockstore, num_slots, &config)
+                .should_clean
+        );
+
+        // An estimate comfortably below the budget skips the scan
+        config.shred_count_estimator = Some(Arc::new(FixedShredCount(Some(89))));
+        let slots_to_clean =
+            BlockstoreCleanupService::find_slots_to_clean(&blockstore, num_slots, &config);
+        assert!(!slots_to_clean.should_clean);
+        assert!(!slots_to_clean.scanned);
+        assert_eq!(slots_to_clean.total_shreds, 89);
+
+        // But not one close to the budget
+        config.shred_count_estimator = Some(Arc::new(FixedShredCount(Some(90))));
+        assert!(
+            BlockstoreCleanupService::find_slots_to_clean(&blockstore, num_slots, &config)
+                .should_clean
+        );
+
+        // Nor when other limits also need the scan
+        config.shred_count_estimator = Some(Arc::new(FixedShredCount(Some(0))));
+        config.max_ledger_duration = Some(Duration::from_secs(3600));
+        assert!(
+            BlockstoreCleanupService::find_slots_to_clean(&blockstore, num_slots, &config)
+                .should_clean
+        );
+    }
+
+    #[test]
+    fn test_purge_rates() {
+        let stats = CleanupStats {
+            slots_purged: 100,
+            shreds_purged: 10_000,
+            purge_duration: Duration::from_secs(2),
+            ..CleanupStats::default()
+        };
+        assert_eq!(stats.slots_purged_per_sec(), 50.0);
+        assert_eq!(stats.shreds_purged_per_sec(), 5_000.0);
+
+        let stats = CleanupStats {
+            slots_purged: 1,
+            shreds_purged: 100,
+            ..CleanupStats::default()
+        };
+        assert_eq!(stats.slots_purged_per_sec(), 1_000.0);
+        assert_eq!(stats.shreds_purged_per_sec(), 100_000.0);
+        assert_eq!(CleanupStats::default().slots_purged_per_sec(), 0.0);
+    }
+
+    #[test]
+    fn test_record_cleanup_peak_total_shreds() {
+        let state = CleanupState::default();
+        for total_shreds in [100, 300, 200] {
+            let stats = CleanupStats {
+                total_shreds,
+                ..CleanupStats::default()
+            };
+            state.record_cleanup(0, &stats, Duration::ZERO);
+        }
+        assert_eq!(state.peak_total_shreds.load(Ordering::Relaxed), 300);
+    }
+
+    #[test]
+    fn test_record_cleanup_over_budget() {
+        let state = CleanupState::default();
+        let stats = CleanupStats {
+            over_budget: true,
+            scanned: true,
+            ..CleanupStats::default()
+        };
+        state.record_cleanup(0, &stats, Duration::ZERO);
+        assert!(state.over_budget.load(Ordering::Relaxed));
+
+        // A cleanup that skipped the scan knows nothing about the budget
+        state.record_cleanup(0, &CleanupStats::default(), Duration::ZERO);
+        assert!(state.over_budget.load(Ordering::Relaxed));
+
+        // Whereas a scan of an empty blockstore does
+        let stats = CleanupStats {
+            scanned: true,
+            ..CleanupStats::default()
+        };
+        state.record_cleanup(0, &stats, Duration::ZERO);
+        assert!(!state.over_budget.load(Ordering::Relaxed));
+    }
+
+    #[test]
+    fn test_record_cleanup_lifetime_shreds_purged() {
+        let state = CleanupState::default();
+        for shreds_purged in [100, 0, 200] {
+            let stats = CleanupStats {
+                shreds_purged,
+                ..CleanupStats::default()
+            };
+            state.record_cleanup(0, &stats, Duration::ZERO);
+        }
+        assert_eq!(state.lifetime_shreds_purged.load(Ordering::Relaxed), 300);
+    }
+
+    #[test]
+    fn test_adaptive_purge_interval() {
+        let bounds = AdaptivePurgeInterval {
+            min_interval: 64,
+            max_interval: 4_096,
+        };
+        // Idle
+        assert_eq!(bounds.purge_interval(1_000_000, 100_000, 0), 4_096);
+        // 900k shreds of headroom takes 9,000 slots to fill at 100 per slot
+        assert_eq!(bounds.purge_interval(1_000_000, 100_000, 100), 4_096);
+        assert_eq!(bounds.purge_interval(1_000_000, 100_000, 300), 1_500);
+        // Heavy load or already over budget
+        assert_eq!(bounds.purge_interval(1_000_000, 100_000, 50_000), 64);
+        assert_eq!(bounds.purge_interval(1_000_000, 2_000_000, 300), 64);
+    }
+
+    #[test]
+    fn test_shred_growth() {
+        let mut shred_growth = ShredGrowth::default();
+        let stats = CleanupStats {
+            total_shreds: 1_000,
+            total_slots: 10,
+            ..CleanupStats::default()
+        };
+        assert_eq!(shred_growth.record(100, &stats), None);
+
+        // 500 shreds were added over 50 slots; 5 slots were then purged
+        let stats = CleanupStats {
+            purged: true,
+            total_shreds: 1_500,
+            total_slots: 15,
+            slots_purged: 5,
+            ..CleanupStats::default()
+        };
+        assert_eq!(shred_growth.record(150, &stats), Some((10, 1_000)));
+
+        // 100 shreds were added over 50 slots
+        let stats = CleanupStats {
+            total_shreds: 1_100,
+            total_slots: 11,
+            ..CleanupStats::default()
+        };
+        assert_eq!(shred_growth.record(200, &stats), Some((2, 1_100)));
+    }
+
+    #[test]
+    fn test_new_min_max_ledger_shreds() {
+        solana_logger::setup();
+        let ledger_path = get_tmp_ledger_path_auto_delete!();
+        let blockstore = Arc::new(Blockstore::open(ledger_path.path()).unwrap());
+        let exit = Arc::new(AtomicBool::new(false));
+
+        // Values below the minimum accepted on the command line are kept as
+        // is, such as the small limits of test validators
+        let service = BlockstoreCleanupService::new(blockstore.clone(), 1_000, exit.clone());
+        assert_eq!(service.max_ledger_shreds(), 1_000);
+        let service2 =
+            BlockstoreCleanupService::new(blockstore, DEFAULT_MAX_LEDGER_SHREDS, exit.clone());
+        assert_eq!(service2.max_ledger_shreds(), DEFAULT_MAX_LEDGER_SHREDS);
+        service2.set_max_ledger_shreds(1_000);
+        assert_eq!(service2.max_ledger_shreds(), 1_000);
+        service2.set_max_ledger_shreds(DEFAULT_MAX_LEDGER_SHREDS / 2);
+        assert_eq!(service2.max_ledger_shreds(), DEFAULT_MAX_LEDGER_SHREDS / 2);
+
+        exit.store(true, Ordering::Relaxed);
+        service.join().unwrap();
+        service2.join().unwrap();
+    }
+
+    #[test]
+    fn test_column_storage_sizes() {
+        solana_logger::setup();
+        let ledger_path = get_tmp_ledger_path_auto_delete!();
+        let blockstore = setup_blockstore_with_slots(ledger_path.path(), 10);
+
+        let column_sizes = blockstore.column_storage_sizes().unwrap();
+        assert!(column_sizes[cf::ShredData::NAME] > 0);
+        assert!(column_sizes[cf::ShredData::NAME] <= blockstore.storage_size().unwrap());
+        assert_eq!(column_sizes.get(cf::Rewards::NAME).copied().unwrap_or(0), 0);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_disk_capacity() {
+        let ledger_path = get_tmp_ledger_path_auto_delete!();
+        let capacity = disk_capacity(ledger_path.path()).unwrap();
+        assert!(capacity > 0);
+        assert!(available_disk_space(ledger_path.path()).unwrap() <= capacity);
+    }
+
+    #[test]
+    fn test_shred_histogram() {
+        solana_logger::setup();
+        let ledger_path = get_tmp_ledger_path_auto_delete!();
+        let blockstore = Blockstore::open(ledger_path.path()).unwrap();
+        let (shreds, _) = make_many_slot_entries(1, 3, 1);
+        blockstore.insert_shreds(shreds, None, false).unwrap();
+        let (shreds, _) = make_many_slot_entries(4, 2, 10_000);
+        blockstore.insert_shreds(shreds, None, false).unwrap();
+
+        let (histogram, num_incomplete_slots) =
+            BlockstoreCleanupService::shred_histogram(&blockstore).unwrap();
+        // Slot 0 is present, with no shreds, as the parent of slot 1
+        assert_eq!(histogram[0], 4);
+        assert_eq!(histogram.iter().sum::<u64>(), 6);
+        assert_eq!(num_incomplete_slots, 1);
+    }
+
+    #[test]
+    fn test_find_slots_to_clean_max_ledger_bytes() {
+        solana_logger::setup();
+        let ledger_path = get_tmp_ledger_path_auto_delete!();
+        let num_slots: u64 = 10;
+        let blockstore = setup_blockstore_with_slots(ledger_path.path(), num_slots);
+
+        // The shred limit alone is not exceeded
+        let mut config = config_with_max_ledger_shreds(u64::MAX);
+        config.max_ledger_bytes = Some(u64::MAX);
+        let SlotsToClean {
+            should_clean,
+            limit,
+            ..
+        } = BlockstoreCleanupService::find_slots_to_clean(&blockstore, num_slots, &config);
+        assert!(!should_clean);
+        assert_eq!(limit, None);
+
+        // The byte limit requires cleaning everything below the root
+        config.max_ledger_bytes = Some(0);
+        let SlotsToClean {
+            should_clean,
+            lowest_cleanup_slot: lowest_purged,
+            limit,
+            ..
+        } = BlockstoreCleanupService::find_slots_to_clean(&blockstore, num_slots, &config);
+        assert!(should_clean);
+        assert_eq!(lowest_purged, num_slots - 1);
+        assert_eq!(limit, Some(RetentionLimit::Bytes));
+
+        // The estimated size is used instead of the storage size if provided
+        config.shred_size_estimator = Some(Arc::new(ConstantShredSizeEstimator(0)));
+        let SlotsToClean { should_clean, .. } =
+            BlockstoreCleanupService::find_slots_to_clean(&blockstore, num_slots, &config);
+        assert!(!should_clean);
+        config.shred_size_estimator = Some(Arc::new(ConstantShredSizeEstimator::default()));
+        let SlotsToClean {
+            should_clean,
+            limit,
+            ..
+        } = BlockstoreCleanupService::find_slots_to_clean(&blockstore, num_slots, &config);
+        assert!(should_clean);
+        assert_eq!(limit, Some(RetentionLimit::Bytes));
+        config.shred_size_estimator = None;
+
+        // The shred limit fires when it requires cleaning more slots
+        config.max_ledger_shreds = 0;
+        config.max_ledger_bytes = Some(u64::MAX);
+        let SlotsToClean {
+            should_clean,
+            limit,
+            ..
+        } = BlockstoreCleanupService::find_slots_to_clean(&blockstore, num_slots, &config);
+        assert!(should_clean);
+        assert_eq!(limit, Some(RetentionLimit::Shreds));
+    }
+
+    #[test]
+    fn test_find_slots_to_clean_max_ledger_duration() {
+        solana_logger::setup();
+        let ledger_path = get_tmp_ledger_path_auto_delete!();
+        let num_slots: u64 = 10;
+        let blockstore = setup_blockstore_with_slots(ledger_path.path(), num_slots);
+
+        // Retain 4 slots worth of history below the root
+        let mut config = config_with_max_ledger_shreds(u64::MAX);
+        config.max_ledger_duration = Some(Duration::from_millis(4 * config.ms_per_slot));
+        assert_eq!(config.max_ledger_slot_window(), Some(4));
+        let SlotsToClean {
+            should_clean,
+            lowest_cleanup_slot: lowest_purged,
+            limit,
+            ..
+        } = BlockstoreCleanupService::find_slots_to_clean(&blockstore, num_slots, &config);
+        assert!(should_clean);
+        assert_eq!(lowest_purged, num_slots - 5);
+        assert_eq!(limit, Some(RetentionLimit::Duration));
+
+        // Nothing to clean when the window covers the entire blockstore
+        config.max_ledger_duration = Some(Duration::from_millis(num_slots * config.ms_per_slot));
+        let SlotsToClean {
+            should_clean,
+            limit,
+            ..
+        } = BlockstoreCleanupService::find_slots_to_clean(&blockstore, num_slots, &config);
+        assert!(!should_clean);
+        assert_eq!(limit, None);
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_find_slots_to_clean_min_free_disk_bytes() {
+        solana_logger::setup();
+        let ledger_path = get_tmp_ledger_path_auto_delete!();
+        let num_slots: u64 = 10;
+        let blockstore = setup_blockstore_with_slots(ledger_path.path(), num_slots);
+        assert!(available_disk_space(ledger_path.path()).unwrap() > 0);
+
+        // Nothing to clean when the volume has enough free space
+        let mut config = config_with_max_ledger_shreds(u64::MAX);
+        config.min_free_disk_bytes = Some(0);
+        let SlotsToClean {
+            should_clean,
+            limit,
+            ..
+        } = BlockstoreCleanupService::find_slots_to_clean(&blockstore, num_slots, &config);
+        assert!(!should_clean);
+        assert_eq!(limit, None);
+
+        // Free space can never recover, so everything below the root is
+        // cleaned
+        config.min_free_disk_bytes = Some(u64::MAX);
+        let SlotsToClean {
+            should_clean,
+            lowest_cleanup_slot: lowest_purged,
+            limit,
+            ..
+        } = BlockstoreCleanupService::find_slots_to_clean(&blockstore, num_slots, &config);
+        assert!(should_clean);
+        assert_eq!(lowest_purged, num_slots - 1);
+        assert_eq!(limit, Some(RetentionLimit::FreeDiskSpace));
+
+        // Unless min_slots_to_retain stops it short
+        config.min_slots_to_retain = 4;
+        let SlotsToClean {
+            should_clean,
+            lowest_cleanup_slot: lowest_purged,
+            ..
+        } = BlockstoreCleanupService::find_slots_to_clean(&blockstore, num_slots, &config);
+        assert!(should_clean);
+        assert_eq!(lowest_purged, num_slots - 4);
+    }
+
+    #[test]
+    fn test_find_slots_to_clean_min_slots_to_retain() {
+        solana_logger::setup();
+        let ledger_path = get_tmp_ledger_path_auto_delete!();
+        let num_slots: u64 = 10;
+        let blockstore = setup_blockstore_with_slots(ledger_path.path(), num_slots);
+
+        // The shred budget would clean everything below the root
+        let mut config = config_with_max_ledger_shreds(0);
+        config.min_slots_to_retain = 3;
+        let SlotsToClean {
+            should_clean,
+            lowest_cleanup_slot: lowest_purged,
+            ..
+        } = BlockstoreCleanupService::find_slots_to_clean(&blockstore, num_slots, &config);
+        assert!(should_clean);
+        assert_eq!(lowest_purged, num_slots - 3);
+
+        // Nothing can be cleaned if the root is within the retained slots
+        config.min_slots_to_retain = num_slots + 1;
+        let SlotsToClean { should_clean, .. } =
+            BlockstoreCleanupService::find_slots_to_clean(&blockstore, num_slots, &config);
+        assert!(!should_clean);
+    }
+
+    #[test]
+    fn test_find_slots_to_clean_repair_retention_slots() {
+        solana_logger::setup();
+        let ledger_path = get_tmp_ledger_path_auto_delete!();
+        let num_slots: u64 = 10;
+        let blockstore = setup_blockstore_with_slots(ledger_path.path(), num_slots);
+
+        // The shred budget would clean everything below the root
+        let mut config = config_with_max_ledger_shreds(0);
+        config.repair_retention_slots = 4;
+        let SlotsToClean {
+            should_clean,
+            lowest_cleanup_slot,
+            ..
+        } = BlockstoreCleanupService::find_slots_to_clean(&blockstore, num_slots, &config);
+        assert!(should_clean);
+        assert_eq!(lowest_cleanup_slot, num_slots - 4);
+
+        // The larger of min_slots_to_retain and repair_retention_slots wins
+        config.min_slots_to_retain = 2;
+        let SlotsToClean {
+            lowest_cleanup_slot,
+            ..
+        } = BlockstoreCleanupService::find_slots_to_clean(&blockstore, num_slots, &config);
+        assert_eq!(lowest_cleanup_slot, num_slots - 4);
+        config.min_slots_to_retain = 6;
+        let SlotsToClean {
+            lowest_cleanup_slot,
+            ..
+        } = BlockstoreCleanupService::find_slots_to_clean(&blockstore, num_slots, &config);
+        assert_eq!(lowest_cleanup_slot, num_slots - 6);
+
+        // By default, nothing this close to the root is cleaned
+        let config = BlockstoreCleanupConfig {
+            max_ledger_shreds: 0,
+            ..BlockstoreCleanupConfig::default()
+        };
+        let SlotsToClean { should_clean, .. } =
+            BlockstoreCleanupService::find_slots_to_clean(&blockstore, num_slots, &config);
+        assert!(!should_clean);
+    }
+
+    #[test]
+    fn test_find_slots_to_clean_cleanup_policy() {
+        solana_logger::setup();
+        let ledger_path = get_tmp_ledger_path_auto_delete!();
+        let num_slots: u64 = 10;
+        let blockstore = setup_blockstore_with_slots(ledger_path.path(), num_slots);
+
+        // ShredCountPolicy matches the shred budget, even though the config's
+        // own budget would not clean anything
+        let mut config = config_with_max_ledger_shreds(u64::MAX);
+        for max_ledger_shreds in [0, 25, 50, u64::MAX] {
+            let SlotsToClean {
+                should_clean,
+                lowest_cleanup_slot: lowest_purged,
+                total_shreds,
+                ..
+            } = BlockstoreCleanupService::find_slots_to_clean(
+                &blockstore,
+                num_slots,
+                &config_with_max_ledger_shreds(max_ledger_shreds),
+            );
+            config.cleanup_policy = Some(Arc::new(ShredCountPolicy::new(
+                &config_with_max_ledger_shreds(max_ledger_shreds),
+            )));
+            let SlotsToClean {
+                should_clean: policy_should_clean,
+                lowest_cleanup_slot: policy_lowest_purged,
+                total_shreds: policy_total_shreds,
+                limit,
+                ..
+            } = BlockstoreCleanupService::find_slots_to_clean(&blockstore, num_slots, &config);
+            assert_eq!(policy_should_clean, should_clean);
+            assert_eq!(policy_lowest_purged, lowest_purged);
+            assert_eq!(policy_total_shreds, total_shreds);
+            assert_eq!(limit, should_clean.then_some(RetentionLimit::Policy));
+        }
+
+        // The policy's choice is still limited by min_slots_to_retain
+        config.cleanup_policy = Some(Arc::new(ShredCountPolicy::new(
+            &config_with_max_ledger_shreds(0),
+        )));
+        config.min_slots_to_retain = 3;
+        let SlotsToClean {
+            should_clean,
+            lowest_cleanup_slot: lowest_purged,
+            ..
+        } = BlockstoreCleanupService::find_slots_to_clean(&blockstore, num_slots, &config);
+        assert!(should_clean);
+        assert_eq!(lowest_purged, num_slots - 3);
+    }
+
+    /// A `CleanupPolicy` that always cleans up to a fixed slot.
+    struct FixedCutoffPolicy(Slot);
+
+    impl CleanupPolicy for FixedCutoffPolicy {
+        fn slots_to_clean(&self, _blockstore: &Blockstore, _root: Slot) -> (bool, Slot, u64) {
+            (true, self.0, 0)
+        }
+    }
+
+    #[test]
+    fn test_cleanup_policy_above_root() {
+        solana_logger::setup();
+        let ledger_path = get_tmp_ledger_path_auto_delete!();
+        let num_slots: u64 = 10;
+        let blockstore = setup_blockstore_with_slots(ledger_path.path(), num_slots);
+        let root = 5;
+        blockstore.set_roots([root].iter()).unwrap();
+
+        // Nothing is cleaned past the root, not even up to it
+        let mut config = config_with_max_ledger_shreds(u64::MAX);
+        config.purge_interval = 0;
+        for cutoff_slot in [root + 1, root] {
+            config.cleanup_policy = Some(Arc::new(FixedCutoffPolicy(cutoff_slot)));
+            let stats = BlockstoreCleanupService::cleanup_ledger_with_config(
+                &blockstore,
+                &config,
+                &mut 0,
+                &AtomicBool::new(false),
+            );
+            assert!(!stats.purged);
+            assert_eq!(blockstore.lowest_cleanup_slot(), 0);
+            for slot in 1..=num_slots {
+                assert!(blockstore.meta(slot).unwrap().is_some());
+            }
+        }
+
+        // Only the slots below it are cleaned
+        config.cleanup_policy = Some(Arc::new(FixedCutoffPolicy(root - 1)));
+        let stats = BlockstoreCleanupService::cleanup_ledger_with_config(
+            &blockstore,
+            &config,
+            &mut 0,
+            &AtomicBool::new(false),
+        );
+        assert!(stats.purged);
+        assert_eq!(blockstore.lowest_cleanup_slot(), root - 1);
+        for slot in 1..root {
+            assert!(blockstore.meta(slot).unwrap().is_none());
+        }
+        for slot in root..=num_slots {
+            assert!(blockstore.meta(slot).unwrap().is_some());
+        }
+    }
+
+    #[test]
+    fn test_find_slots_to_clean_epoch_schedule() {
+        solana_logger::setup();
+        let ledger_path = get_tmp_ledger_path_auto_delete!();
+        let num_slots: u64 = 80;
+        let blockstore = setup_blockstore_with_slots(ledger_path.path(), num_slots);
+
+        // Epochs of 32 slots: [0, 31], [32, 63], [64, 95]
+        let mut config = config_with_max_ledger_shreds(0);
+        config.epoch_schedule = Some(EpochSchedule::custom(32, 32, false));
+
+        // The cleanup is snapped down to the end of epoch 1
+        let SlotsToClean {
+            should_clean,
+            lowest_cleanup_slot: lowest_purged,
+            ..
+        } = BlockstoreCleanupService::find_slots_to_clean(&blockstore, num_slots, &config);
+        assert!(should_clean);
+        assert_eq!(lowest_purged, 63);
+
+        // A cleanup that already ends an epoch is left alone
+        config.min_slots_to_retain = num_slots - 63;
+        let SlotsToClean {
+            should_clean,
+            lowest_cleanup_slot: lowest_purged,
+            ..
+        } = BlockstoreCleanupService::find_slots_to_clean(&blockstore, num_slots, &config);
+        assert!(should_clean);
+        assert_eq!(lowest_purged, 63);
+
+        // Nothing is cleaned if that would leave part of the first epoch
+        config.min_slots_to_retain = num_slots - 20;
+        let SlotsToClean { should_clean, .. } =
+            BlockstoreCleanupService::find_slots_to_clean(&blockstore, num_slots, &config);
+        assert!(!should_clean);
+    }
+
+    #[test]
+    fn test_cleanup_pinned_slots() {
+        solana_logger::setup();
+        let ledger_path = get_tmp_ledger_path_auto_delete!();
+        let num_slots: u64 = 10;
+        let blockstore = setup_blockstore_with_slots(ledger_path.path(), num_slots);
+        blockstore.set_roots([num_slots].iter()).unwrap();
+
+        let mut config = config_with_max_ledger_shreds(0);
+        config.purge_interval = 0;
+        config.pinned_slots.write().unwrap().extend([4, 7]);
+        let mut last_purge_slot = 0;
+        let stats = BlockstoreCleanupService::cleanup_ledger_with_config(
+            &blockstore,
+            &config,
+            &mut last_purge_slot,
+            &AtomicBool::new(false),
+        );
+        assert!(stats.purged);
+        assert_eq!(stats.lowest_cleanup_slot, 3);
+        assert!(stats.over_budget);
+        assert!(blockstore.meta(3).unwrap().is_none());
+        assert!(blockstore.meta(4).unwrap().is_some());
+
+        // Unpinning the lowest slot lets the cleanup proceed up to the next one
+        config.pinned_slots.write().unwrap().remove(&4);
+        let mut last_purge_slot = 0;
+        let stats = BlockstoreCleanupService::cleanup_ledger_with_config(
+            &blockstore,
+            &config,
+            &mut last_purge_slot,
+            &AtomicBool::new(false),
+        );
+        assert!(stats.purged);
+        assert_eq!(stats.lowest_cleanup_slot, 6);
+        for slot in 1..7 {
+            assert!(blockstore.meta(slot).unwrap().is_none());
+        }
+        for slot in 7..=num_slots {
+            assert!(blockstore.meta(slot).unwrap().is_some());
+        }
+    }
+
+    #[test]
+    fn test_cleanup_skips_redundant_purge() {
+        solana_logger::setup();
+        let ledger_path = get_tmp_ledger_path_auto_delete!();
+        let num_slots: u64 = 10;
+        let blockstore = setup_blockstore_with_slots(ledger_path.path(), num_slots);
+        blockstore.set_roots([num_slots].iter()).unwrap();
+
+        let mut config = config_with_max_ledger_shreds(0);
+        config.purge_interval = 0;
+        config.protected_slot_floor = Some(Arc::new(AtomicU64::new(6)));
+        let purges = Arc::new(AtomicU64::new(0));
+        let on_purge_purges = purges.clone();
+        config.on_purge = Some(Arc::new(move |_, _| {
+            on_purge_purges.fetch_add(1, Ordering::Relaxed);
+        }));
+
+        let mut last_purge_slot = 0;
+        let stats = BlockstoreCleanupService::cleanup_ledger_at_root(
+            &blockstore,
+            num_slots,
+            &config,
+            &mut last_purge_slot,
+            &AtomicBool::new(false),
+        );
+        assert!(stats.purged);
+        assert!(!stats.noop);
+        assert_eq!(stats.lowest_cleanup_slot, 5);
+        assert_eq!(stats.retained_slots, num_slots - 5);
+        assert_eq!(purges.load(Ordering::Relaxed), 1);
+
+        // The floor holds the cutoff in place, so a second cycle at the same
+        // root has nothing left to purge
+        let mut last_purge_slot = 0;
+        let stats = BlockstoreCleanupService::cleanup_ledger_at_root(
+            &blockstore,
+            num_slots,
+            &config,
+            &mut last_purge_slot,
+            &AtomicBool::new(false),
+        );
+        assert!(!stats.purged);
+        assert!(stats.noop);
+        assert_eq!(purges.load(Ordering::Relaxed), 1);
+        // The retained history is still reported
+        assert_eq!(stats.retained_slots, num_slots - 5);
+        for slot in 6..=num_slots {
+            assert!(blockstore.meta(slot).unwrap().is_some());
+        }
+    }
+
+    #[test]
+    fn test_cleanup_purge_events() {
+        solana_logger::setup();
+        let ledger_path = get_tmp_ledger_path_auto_delete!();
+        let num_slots: u64 = 10;
+        let blockstore = setup_blockstore_with_slots(ledger_path.path(), num_slots);
+        blockstore.set_roots([num_slots].iter()).unwrap();
+
+        let (sender, receiver) = crossbeam_channel::bounded(1);
+        let protected_slot_floor = Arc::new(AtomicU64::new(6));
+        let mut config = config_with_max_ledger_shreds(0);
+        config.purge_interval = 0;
+        config.protected_slot_floor = Some(protected_slot_floor.clone());
+        config.purge_event_sender = Some(sender);
+        let mut last_purge_slot = 0;
+        let stats = BlockstoreCleanupService::cleanup_ledger_with_config(
+            &blockstore,
+            &config,
+            &mut last_purge_slot,
+            &AtomicBool::new(false),
+        );
+        assert!(stats.purged);
+        assert!(!stats.purge_event_dropped);
+        let event = receiver.try_recv().unwrap();
+        assert_eq!((event.from, event.to), (1, 5));
+        assert!(event.shreds > 0);
+
+        // With the channel full, the next event is dropped rather than
+        // blocking the cleanup
+        let sender = config.purge_event_sender.as_ref().unwrap();
+        sender.try_send(event).unwrap();
+        protected_slot_floor.store(num_slots + 1, Ordering::Relaxed);
+        let mut last_purge_slot = 0;
+        let stats = BlockstoreCleanupService::cleanup_ledger_with_config(
+            &blockstore,
+            &config,
+            &mut last_purge_slot,
+            &AtomicBool::new(false),
+        );
+        assert!(stats.purged);
+        assert!(stats.purge_event_dropped);
+        assert_eq!(receiver.try_recv().unwrap(), event);
+        assert!(receiver.try_recv().is_err());
+    }
+
+    #[test]
+    fn test_cleanup_max_ledger_shreds_provider() {
+        solana_logger::setup();
+        let ledger_path = get_tmp_ledger_path_auto_delete!();
+        let num_slots: u64 = 10;
+        let blockstore = setup_blockstore_with_slots(ledger_path.path(), num_slots);
+        blockstore.set_roots([num_slots].iter()).unwrap();
+
+        // The configured budget is never exceeded
+        let mut config = config_with_max_ledger_shreds(u64::MAX);
+        config.purge_interval = 0;
+        let mut last_purge_slot = 0;
+        let stats = BlockstoreCleanupService::cleanup_ledger_with_config(
+            &blockstore,
+            &config,
+            &mut last_purge_slot,
+            &AtomicBool::new(false),
+        );
+        assert!(!stats.purged);
+        assert!(stats.total_shreds > 0);
+        assert!(blockstore.meta(1).unwrap().is_some());
+
+        // The provided budget overrides it, and is used as is
+        let num_calls = Arc::new(AtomicU64::new(0));
+        let provider_num_calls = num_calls.clone();
+        config.max_ledger_shreds_provider = Some(Arc::new(move || {
+            provider_num_calls.fetch_add(1, Ordering::Relaxed);
+            0
+        }));
+        let mut last_purge_slot = 0;
+        let stats = BlockstoreCleanupService::cleanup_ledger_with_config(
+            &blockstore,
+            &config,
+            &mut last_purge_slot,
+            &AtomicBool::new(false),
+        );
+        assert_eq!(num_calls.load(Ordering::Relaxed), 1);
+        assert!(stats.purged);
+        assert_eq!(stats.lowest_cleanup_slot, num_slots - 1);
+        assert!(blockstore.meta(num_slots - 1).unwrap().is_none());
+        assert!(blockstore.meta(num_slots).unwrap().is_some());
+    }
+
+    #[test]
+    fn test_cleanup_metadata_retention_slots() {
+        solana_logger::setup();
+        let ledger_path = get_tmp_ledger_path_auto_delete!();
+        let num_slots: u64 = 10;
+        let blockstore = setup_blockstore_with_slots(ledger_path.path(), num_slots);
+        blockstore.set_roots([num_slots].iter()).unwrap();
+        let signature = Signature::from([7u8; 64]);
+        let address = Pubkey::new_unique();
+        blockstore
+            .write_transaction_status(
+                3,
+                signature,
+                [(&address, true)].into_iter(),
+                TransactionStatusMeta::default(),
+                0,
+            )
+            .unwrap();
+
+        let mut config = config_with_max_ledger_shreds(0);
+        config.purge_interval = 0;
+        config.purge_type = PurgeType::Exact;
+        config.metadata_retention_slots = Some(7);
+        let mut last_purge_slot = 0;
+        let stats = BlockstoreCleanupService::cleanup_ledger_with_config(
+            &blockstore,
+            &config,
+            &mut last_purge_slot,
+            &AtomicBool::new(false),
+        );
+        assert!(stats.purged);
+        assert!(stats.lowest_cleanup_slot > 7);
+        assert!(blockstore.meta(3).unwrap().is_none());
+        // The metadata expires 7 slots behind the shreds rather than with
+        // them, and is not purged along with them
+        assert_eq!(
+            blockstore.max_expired_slot(),
+            Some(stats.lowest_cleanup_slot - 7)
+        );
+        assert!(blockstore
+            .read_transaction_status((signature, 3))
+            .unwrap()
+            .is_some());
+
+        // Without it, the metadata expires with the shreds
+        config.metadata_retention_slots = None;
+        BlockstoreCleanupService::purge_slots(
+            &blockstore,
+            0,
+            1,
+            stats.lowest_cleanup_slot,
+            &config,
+            &AtomicBool::new(false),
+            &mut CleanupStats::default(),
+        )
+        .unwrap();
+        assert_eq!(
+            blockstore.max_expired_slot(),
+            Some(stats.lowest_cleanup_slot)
+        );
+    }
+
+    #[test]
+    fn test_purge_slots_failure_keeps_lowest_cleanup_slot() {
+        solana_logger::setup();
+        let ledger_path = get_tmp_ledger_path_auto_delete!();
+        let num_slots: u64 = 10;
+        let blockstore = setup_blockstore_with_slots(ledger_path.path(), num_slots);
+
+        // Writes to a secondary instance fail, so every purge does
+        let secondary = Blockstore::open_with_options(
+            ledger_path.path(),
+            BlockstoreOptions {
+                access_type: AccessType::Secondary,
+                ..BlockstoreOptions::default()
+            },
+        )
+        .unwrap();
+        *secondary.lowest_cleanup_slot.write().unwrap() = 2;
+        let result = BlockstoreCleanupService::purge_slots(
+            &secondary,
+            0,
+            1,
+            5,
+            &config_with_max_ledger_shreds(0),
+            &AtomicBool::new(false),
+            &mut CleanupStats::default(),
+        );
+        assert!(result.is_err());
+        assert_eq!(secondary.lowest_cleanup_slot(), 2);
+        assert_eq!(secondary.max_expired_slot(), None);
+        for slot in 1..=num_slots {
+            assert!(blockstore.meta(slot).unwrap().is_some());
+        }
+    }
+
+    #[test]
+    fn test_cleanup_snapshot_in_progress() {
+        solana_logger::setup();
+        let ledger_path = get_tmp_ledger_path_auto_delete!();
+        let num_slots: u64 = 10;
+        let blockstore = setup_blockstore_with_slots(ledger_path.path(), num_slots);
+        blockstore.set_roots([num_slots].iter()).unwrap();
+
+        let snapshot_in_progress = Arc::new(AtomicBool::new(true));
+        let mut config = config_with_max_ledger_shreds(0);
+        config.purge_interval = 0;
+        config.snapshot_in_progress = Some(snapshot_in_progress.clone());
+        let mut last_purge_slot = 0;
+        let stats = BlockstoreCleanupService::cleanup_ledger_with_config(
+            &blockstore,
+            &config,
+            &mut last_purge_slot,
+            &AtomicBool::new(false),
+        );
+        assert!(!stats.purged);
+        assert!(stats.purge_vetoed);
+        assert_eq!(last_purge_slot, 0);
+        assert_eq!(blockstore.lowest_cleanup_slot(), 0);
+        assert!(blockstore.meta(1).unwrap().is_some());
+
+        // Once the snapshot is done, the deferred cleanup goes ahead
+        snapshot_in_progress.store(false, Ordering::Release);
+        let stats = BlockstoreCleanupService::cleanup_ledger_with_config(
+            &blockstore,
+            &config,
+            &mut last_purge_slot,
+            &AtomicBool::new(false),
+        );
+        assert!(stats.purged);
+        assert_eq!(last_purge_slot, num_slots);
+        assert!(blockstore.meta(1).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_cleanup_purge_veto() {
+        solana_logger::setup();
+        let ledger_path = get_tmp_ledger_path_auto_delete!();
+        let num_slots: u64 = 10;
+        let blockstore = setup_blockstore_with_slots(ledger_path.path(), num_slots);
+        blockstore.set_roots([num_slots].iter()).unwrap();
+
+        let allow_purge = Arc::new(AtomicBool::new(false));
+        let proposed_slot = Arc::new(AtomicU64::new(0));
+        let mut config = config_with_max_ledger_shreds(0);
+        config.purge_interval = 0;
+        config.purge_veto = Some({
+            let allow_purge = allow_purge.clone();
+            let proposed_slot = proposed_slot.clone();
+            Arc::new(move |lowest_cleanup_slot| {
+                proposed_slot.store(lowest_cleanup_slot, Ordering::Relaxed);
+                allow_purge.load(Ordering::Relaxed)
+            })
+        });
+        let mut last_purge_slot = 0;
+        let stats = BlockstoreCleanupService::cleanup_ledger_with_config(
+            &blockstore,
+            &config,
+            &mut last_purge_slot,
+            &AtomicBool::new(false),
+        );
+        assert!(!stats.purged);
+        assert!(stats.purge_vetoed);
+        assert_eq!(proposed_slot.load(Ordering::Relaxed), num_slots - 1);
+        assert_eq!(last_purge_slot, 0);
+        assert!(blockstore.meta(1).unwrap().is_some());
+
+        // The deferred cleanup is retried without the root advancing
+        allow_purge.store(true, Ordering::Relaxed);
+        let stats = BlockstoreCleanupService::cleanup_ledger_with_config(
+            &blockstore,
+            &config,
+            &mut last_purge_slot,
+            &AtomicBool::new(false),
+        );
+        assert!(stats.purged);
+        assert!(!stats.purge_vetoed);
+        assert_eq!(last_purge_slot, num_slots);
+        assert!(blockstore.meta(num_slots - 1).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_scan_starts_at_lowest_cleanup_slot() {
+        solana_logger::setup();
+        let ledger_path = get_tmp_ledger_path_auto_delete!();
+        let num_slots: u64 = 50;
+        let blockstore = setup_blockstore_with_slots(ledger_path.path(), num_slots);
+        blockstore.set_roots([num_slots].iter()).unwrap();
+
+        let mut config = config_with_max_ledger_shreds(0);
+        config.purge_interval = 0;
+        config.protected_slot_floor = Some(Arc::new(AtomicU64::new(41)));
+        let mut last_purge_slot = 0;
+        let stats = BlockstoreCleanupService::cleanup_ledger_with_config(
+            &blockstore,
+            &config,
+            &mut last_purge_slot,
+            &AtomicBool::new(false),
+        );
+        assert_eq!(stats.lowest_cleanup_slot, 40);
+        assert_eq!(blockstore.lowest_cleanup_slot(), 40);
+
+        // The next scan covers only the slots above the purged ones
+        assert_eq!(
+            BlockstoreCleanupService::lowest_slot(&blockstore, &config).unwrap(),
+            41
+        );
+        let SlotsToClean { total_slots, .. } =
+            BlockstoreCleanupService::find_slots_to_clean(&blockstore, num_slots, &config);
+        assert_eq!(total_slots, num_slots - 40);
+        assert_eq!(config.lowest_existing_slot.load(Ordering::Relaxed), 41);
+    }
+
+    #[test]
+    fn test_lowest_existing_slot() {
+        solana_logger::setup();
+        let ledger_path = get_tmp_ledger_path_auto_delete!();
+        let blockstore = Blockstore::open(ledger_path.path()).unwrap();
+        // As if booted from a snapshot at slot 20
+        let (shreds, _) = make_many_slot_entries(21, 10, 200);
+        blockstore.insert_shreds(shreds, None, false).unwrap();
+        let blockstore = Arc::new(flush_blockstore_contents_to_disk(blockstore));
+        blockstore.set_roots([30].iter()).unwrap();
+
+        let mut config = config_with_max_ledger_shreds(0);
+        config.purge_interval = 0;
+        // Chaining slot 21 leaves an empty SlotMeta behind for its parent
+        BlockstoreCleanupService::update_lowest_existing_slot(&blockstore, &config);
+        assert_eq!(config.lowest_existing_slot.load(Ordering::Relaxed), 20);
+
+        let purged_from = Arc::new(AtomicU64::new(0));
+        let purged_from_clone = purged_from.clone();
+        config.on_purge = Some(Arc::new(move |from_slot, _| {
+            purged_from_clone.store(from_slot, Ordering::Relaxed);
+        }));
+        let mut last_purge_slot = 0;
+        let stats = BlockstoreCleanupService::cleanup_ledger_with_config(
+            &blockstore,
+            &config,
+            &mut last_purge_slot,
+            &AtomicBool::new(false),
+        );
+        assert!(stats.purged);
+        assert_eq!(purged_from.load(Ordering::Relaxed), 20);
+        assert_eq!(
+            config.lowest_existing_slot.load(Ordering::Relaxed),
+            stats.lowest_cleanup_slot
+        );
+    }
+
+    #[test]
+    fn test_cleanup_confirmation_lag() {
+        solana_logger::setup();
+        let ledger_path = get_tmp_ledger_path_auto_delete!();
+        let num_slots: u64 = 10;
+        let blockstore = setup_blockstore_with_slots(ledger_path.path(), num_slots);
+        blockstore.set_roots([num_slots].iter()).unwrap();
+
+        let mut config = config_with_max_ledger_shreds(0);
+        config.purge_interval = 0;
+        config.confirmation_lag = 4;
+        let mut last_purge_slot = 0;
+        let stats = BlockstoreCleanupService::cleanup_ledger_with_config(
+            &blockstore,
+            &config,
+            &mut last_purge_slot,
+            &AtomicBool::new(false),
+        );
+        assert!(stats.purged);
+        assert_eq!(stats.lowest_cleanup_slot, num_slots - 5);
+        assert_eq!(last_purge_slot, num_slots);
+        for slot in 1..=num_slots - 5 {
+            assert!(blockstore.meta(slot).unwrap().is_none());
+        }
+        for slot in num_slots - 4..=num_slots {
+            assert!(blockstore.meta(slot).unwrap().is_some());
+        }
+    }
+
+    #[test]
+    fn test_cleanup_ledger_at_root() {
+        solana_logger::setup();
+        let ledger_path = get_tmp_ledger_path_auto_delete!();
+        let num_slots: u64 = 10;
+        let blockstore = setup_blockstore_with_slots(ledger_path.path(), num_slots);
+
+        // The given root is used even though the blockstore has none
+        let mut config = config_with_max_ledger_shreds(0);
+        config.purge_interval = 0;
+        let mut last_purge_slot = 0;
+        let stats = BlockstoreCleanupService::cleanup_ledger_at_root(
+            &blockstore,
+            5,
+            &config,
+            &mut last_purge_slot,
+            &AtomicBool::new(false),
+        );
+        assert!(stats.purged);
+        assert_eq!(stats.lowest_cleanup_slot, 4);
+        assert_eq!(last_purge_slot, 5);
+        assert!(blockstore.meta(4).unwrap().is_none());
+        assert!(blockstore.meta(5).unwrap().is_some());
+    }
+
+    #[test]
+    fn test_purge_below() {
+        solana_logger::setup();
+        let ledger_path = get_tmp_ledger_path_auto_delete!();
+        let num_slots: u64 = 10;
+        let blockstore = setup_blockstore_with_slots(ledger_path.path(), num_slots);
+
+        BlockstoreCleanupService::purge_below(&blockstore, 6, PurgeType::Exact).unwrap();
+        assert_eq!(blockstore.lowest_cleanup_slot(), 6);
+        for slot in 1..=6 {
+            assert!(blockstore.meta(slot).unwrap().is_none());
+        }
+        for slot in 7..=num_slots {
+            assert!(blockstore.meta(slot).unwrap().is_some());
+        }
+    }
+
+    #[test]
+    fn test_cleanup_protected_slot_floor() {
+        solana_logger::setup();
+        let ledger_path = get_tmp_ledger_path_auto_delete!();
+        let num_slots: u64 = 10;
+        let blockstore = setup_blockstore_with_slots(ledger_path.path(), num_slots);
+        blockstore.set_roots([num_slots].iter()).unwrap();
+
+        let protected_slot_floor = 6;
+        let mut config = config_with_max_ledger_shreds(0);
+        config.purge_interval = 0;
+        config.protected_slot_floor = Some(Arc::new(AtomicU64::new(protected_slot_floor)));
+        let mut last_purge_slot = 0;
+        let stats = BlockstoreCleanupService::cleanup_ledger_with_config(
+            &blockstore,
+            &config,
+            &mut last_purge_slot,
+            &AtomicBool::new(false),
+        );
+        assert!(stats.purged);
+        assert_eq!(stats.lowest_cleanup_slot, protected_slot_floor - 1);
+        for slot in 1..protected_slot_floor {
+            assert!(blockstore.meta(slot).unwrap().is_none());
+        }
+        for slot in protected_slot_floor..=num_slots {
+            assert!(blockstore.meta(slot).unwrap().is_some());
+        }
+    }
+
+    #[test]
+    fn test_cleanup_max_slots_per_purge() {
+        solana_logger::setup();
+        let ledger_path = get_tmp_ledger_path_auto_delete!();
+        let num_slots: u64 = 10;
+        let blockstore = setup_blockstore_with_slots(ledger_path.path(), num_slots);
+        blockstore.set_roots([num_slots].iter()).unwrap();
+
+        let mut config = config_with_max_ledger_shreds(0);
+        config.purge_interval = 0;
+        config.max_slots_per_purge = Some(3);
+
+        // With exit already set, only the first step is performed
+        let mut last_purge_slot = 0;
+        let stats = BlockstoreCleanupService::cleanup_ledger_with_config(
+            &blockstore,
+            &config,
+            &mut last_purge_slot,
+            &AtomicBool::new(true),
+        );
+        assert_eq!(stats.lowest_cleanup_slot, 3);
+        assert_eq!(blockstore.lowest_cleanup_slot(), 3);
+        assert!(blockstore.meta(3).unwrap().is_none());
+        assert!(blockstore.meta(4).unwrap().is_some());
+        assert_eq!(config.purge_progress.load(Ordering::Relaxed), 33);
+
+        // Otherwise, all of the steps are performed
+        let mut last_purge_slot = 0;
+        let stats = BlockstoreCleanupService::cleanup_ledger_with_config(
+            &blockstore,
+            &config,
+            &mut last_purge_slot,
+            &AtomicBool::new(false),
+        );
+        assert_eq!(stats.lowest_cleanup_slot, num_slots - 1);
+        assert_eq!(blockstore.lowest_cleanup_slot(), num_slots - 1);
+        assert!(blockstore.meta(num_slots - 1).unwrap().is_none());
+        assert_eq!(config.purge_progress.load(Ordering::Relaxed), 100);
+    }
+
+    #[test]
+    fn test_cleanup_root_regressed() {
+        solana_logger::setup();
+        let ledger_path = get_tmp_ledger_path_auto_delete!();
+        let num_slots: u64 = 10;
+        let blockstore = setup_blockstore_with_slots(ledger_path.path(), num_slots);
+        blockstore.set_roots([num_slots].iter()).unwrap();
+
+        let mut config = config_with_max_ledger_shreds(0);
+        config.purge_interval = 0;
+
+        // Cleanups that previously saw larger roots are skipped
+        for last_seen_root in (num_slots + 1..=num_slots + 3).rev() {
+            let mut last_purge_slot = last_seen_root;
+            let stats = BlockstoreCleanupService::cleanup_ledger_with_config(
+                &blockstore,
+                &config,
+                &mut last_purge_slot,
+                &AtomicBool::new(false),
+            );
+            assert_eq!(stats, CleanupStats::default());
+            assert_eq!(last_purge_slot, last_seen_root);
+        }
+        assert_eq!(blockstore.lowest_cleanup_slot(), 0);
+        for slot in 1..=num_slots {
+            assert!(blockstore.meta(slot).unwrap().is_some());
+        }
+    }
+
+    #[test]
+    fn test_cleanup_repeated_root() {
+        solana_logger::setup();
+        let ledger_path = get_tmp_ledger_path_auto_delete!();
+        let num_slots: u64 = 10;
+        let blockstore = setup_blockstore_with_slots(ledger_path.path(), num_slots);
+        blockstore.set_roots([num_slots].iter()).unwrap();
+
+        // A budget that is never exceeded, so that nothing is purged
+        let mut config = config_with_max_ledger_shreds(u64::MAX);
+        config.purge_interval = 0;
+        let mut last_purge_slot = 0;
+        let stats = BlockstoreCleanupService::cleanup_ledger_with_config(
+            &blockstore,
+            &config,
+            &mut last_purge_slot,
+            &AtomicBool::new(false),
+        );
+        assert!(stats.total_shreds > 0);
+        assert!(!stats.purged);
+        assert_eq!(last_purge_slot, num_slots);
+
+        // Seeing the same root again doesn't scan the blockstore again
+        let stats = BlockstoreCleanupService::cleanup_ledger_with_config(
+            &blockstore,
+            &config,
+            &mut last_purge_slot,
+            &AtomicBool::new(false),
+        );
+        assert_eq!(stats, CleanupStats::default());
+        assert_eq!(last_purge_slot, num_slots);
+    }
+
+    #[test]
+    fn test_cleanup_report_disk_metrics() {
+        solana_logger::setup();
+        let num_slots: u64 = 10;
+        for report_disk_metrics in [true, false] {
+            let ledger_path = get_tmp_ledger_path_auto_delete!();
+            let blockstore = setup_blockstore_with_slots(ledger_path.path(), num_slots);
+            blockstore.set_roots([num_slots].iter()).unwrap();
+
+            let mut config = config_with_max_ledger_shreds(0);
+            config.purge_interval = 0;
+            config.report_disk_metrics = report_disk_metrics;
+            let mut last_purge_slot = 0;
+            let stats = BlockstoreCleanupService::cleanup_ledger_with_config(
+                &blockstore,
+                &config,
+                &mut last_purge_slot,
+                &AtomicBool::new(false),
+            );
+            // The cleanup itself is unaffected
+            assert!(stats.purged);
+            assert_eq!(stats.lowest_cleanup_slot, num_slots - 1);
+            assert_eq!(
+                stats.purge_disk_utilization_delta.is_some(),
+                report_disk_metrics
+            );
+        }
+    }
+
+    #[test]
+    fn test_cleanup_metrics_settle_delay() {
+        solana_logger::setup();
+        let ledger_path = get_tmp_ledger_path_auto_delete!();
+        let num_slots: u64 = 10;
+        let blockstore = setup_blockstore_with_slots(ledger_path.path(), num_slots);
+        blockstore.set_roots([num_slots].iter()).unwrap();
+
+        let clock = Arc::new(ManualClock::new());
+        let mut config = config_with_max_ledger_shreds(0);
+        config.purge_interval = 0;
+        config.exit_poll_interval = Duration::from_secs(1);
+        config.metrics_settle_delay = Some(Duration::from_secs(10));
+        config.clock = clock.clone();
+
+        // The storage size is checked at least once after the purge, but the
+        // wait ends once it stops changing
+        let mut last_purge_slot = 0;
+        let stats = BlockstoreCleanupService::cleanup_ledger_with_config(
+            &blockstore,
+            &config,
+            &mut last_purge_slot,
+            &AtomicBool::new(false),
+        );
+        assert!(stats.purged);
+        let slept = clock.slept();
+        assert!(slept >= Duration::from_secs(1));
+        assert!(slept <= Duration::from_secs(10));
+
+        // Nothing is waited for without a purge
+        let mut last_purge_slot = 0;
+        let stats = BlockstoreCleanupService::cleanup_ledger_with_config(
+            &blockstore,
+            &config,
+            &mut last_purge_slot,
+            &AtomicBool::new(false),
+        );
+        assert!(!stats.purged);
+        assert_eq!(clock.slept(), slept);
+    }
+
+    #[test]
+    fn test_cleanup_max_purge_bytes_per_sec() {
+        solana_logger::setup();
+        let ledger_path = get_tmp_ledger_path_auto_delete!();
+        let num_slots: u64 = 10;
+        let blockstore = setup_blockstore_with_slots(ledger_path.path(), num_slots);
+        blockstore.set_roots([num_slots].iter()).unwrap();
+
+        let clock = Arc::new(ManualClock::new());
+        let mut config = config_with_max_ledger_shreds(0);
+        config.purge_interval = 0;
+        config.max_slots_per_purge = Some(5);
+        config.exit_poll_interval = Duration::MAX;
+        config.clock = clock.clone();
+
+        // Without a cap, the steps follow each other immediately
+        let mut last_purge_slot = 0;
+        BlockstoreCleanupService::cleanup_ledger_with_config(
+            &blockstore,
+            &config,
+            &mut last_purge_slot,
+            &AtomicBool::new(false),
+        );
+        assert_eq!(clock.slept(), Duration::ZERO);
+
+        // With a cap of about one slot's worth of bytes per second, the first
+        // step of 5 slots is followed by a wait of about 5 seconds; no wait
+        // follows the last step
+        let ledger_path = get_tmp_ledger_path_auto_delete!();
+        let blockstore = setup_blockstore_with_slots(ledger_path.path(), num_slots);
+        blockstore.set_roots([num_slots].iter()).unwrap();
+        let num_bytes = blockstore.storage_size().unwrap();
+        config.max_purge_bytes_per_sec = Some(num_bytes / num_slots);
+        let mut last_purge_slot = 0;
+        let stats = BlockstoreCleanupService::cleanup_ledger_with_config(
+            &blockstore,
+            &config,
+            &mut last_purge_slot,
+            &AtomicBool::new(false),
+        );
+        assert_eq!(stats.lowest_cleanup_slot, num_slots - 1);
+        assert!(clock.slept() >= Duration::from_secs(4));
+        assert!(clock.slept() <= Duration::from_secs(6));
+        assert_eq!(stats.throttle_duration, clock.slept());
+    }
+
+    #[test]
+    fn test_blockstore_archiver() {
+        solana_logger::setup();
+        let ledger_path = get_tmp_ledger_path_auto_delete!();
+        let num_slots: u64 = 10;
+        let blockstore = setup_blockstore_with_slots(ledger_path.path(), num_slots);
+        blockstore
+            .set_roots([num_slots - 1, num_slots].iter())
+            .unwrap();
+        let secondary_path = get_tmp_ledger_path_auto_delete!();
+        let secondary = Arc::new(Blockstore::open(secondary_path.path()).unwrap());
+
+        let mut config = config_with_max_ledger_shreds(0);
+        config.purge_interval = 0;
+        config.archiver = Some(Arc::new(BlockstoreArchiver::new(secondary.clone(), 0)));
+        let mut last_purge_slot = 0;
+        let stats = BlockstoreCleanupService::cleanup_ledger_with_config(
+            &blockstore,
+            &config,
+            &mut last_purge_slot,
+            &AtomicBool::new(false),
+        );
+        assert!(stats.purged);
+        assert!(stats.archived);
+
+        // The purged slots live on in the secondary blockstore
+        for slot in 1..num_slots {
+            assert!(blockstore.meta(slot).unwrap().is_none());
+            assert!(secondary.meta(slot).unwrap().unwrap().is_full());
+        }
+        assert!(secondary.is_root(num_slots - 1));
+        assert_eq!(secondary.max_root(), num_slots - 1);
+        assert!(secondary.meta(num_slots).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_cleanup_compact_after_purge() {
+        solana_logger::setup();
+        let ledger_path = get_tmp_ledger_path_auto_delete!();
+        let num_slots: u64 = 10;
+        let blockstore = setup_blockstore_with_slots(ledger_path.path(), num_slots);
+        blockstore.set_roots([num_slots].iter()).unwrap();
+
+        let mut config = config_with_max_ledger_shreds(0);
+        config.purge_interval = 0;
+        config.compact_after_purge = true;
+
+        let mut last_purge_slot = 0;
+        let stats = BlockstoreCleanupService::cleanup_ledger_with_config(
+            &blockstore,
+            &config,
+            &mut last_purge_slot,
+            &AtomicBool::new(false),
+        );
+        assert!(stats.purged);
+        assert!(!stats.archived);
+        assert_eq!(stats.lowest_cleanup_slot, num_slots - 1);
+        for slot in 1..num_slots {
+            assert!(blockstore.meta(slot).unwrap().is_none());
+            assert!(blockstore
+                .get_data_shreds_for_slot(slot, 0)
+                .unwrap()
+                .is_empty());
+        }
+    }
+
+    /// A `Clock` that only advances when told to, and that keeps track of
+    /// how long it was asked to sleep.
+    struct ManualClock {
+        now: std::sync::Mutex<Instant>,
+        slept: std::sync::Mutex<Duration>,
+        num_sleeps: AtomicU64,
+    }
+
+    impl ManualClock {
+        fn new() -> Self {
+            Self {
+                now: std::sync::Mutex::new(Instant::now()),
+                slept: std::sync::Mutex::default(),
+                num_sleeps: AtomicU64::default(),
+            }
+        }
+
+        fn advance(&self, duration: Duration) {
+            *self.now.lock().unwrap() += duration;
+        }
+
+        fn slept(&self) -> Duration {
+            *self.slept.lock().unwrap()
+        }
+
+        /// Waits until the cleanup thread has checked whether a cleanup is
+        /// due at least once since this was called. The thread sleeps after
+        /// each check, and the next sleep may follow a check made before the
+        /// call, so this waits for two.
+        fn wait_for_check(&self) {
+            let num_sleeps = self.num_sleeps.load(Ordering::Relaxed);
+            let start = Instant::now();
+            while self.num_sleeps.load(Ordering::Relaxed) < num_sleeps + 2 {
+                assert!(start.elapsed() < Duration::from_secs(10));
+                thread::sleep(Duration::from_millis(1));
+            }
+        }
+    }
+
+    impl Clock for ManualClock {
+        fn now(&self) -> Instant {
+            *self.now.lock().unwrap()
+        }
+
+        fn sleep(&self, duration: Duration) {
+            *self.slept.lock().unwrap() += duration;
+            self.num_sleeps.fetch_add(1, Ordering::Relaxed);
+            thread::sleep(Duration::from_millis(1));
+        }
+    }
+
+    #[test]
+    fn test_min_wall_interval_elapsed() {
+        let clock = Arc::new(ManualClock::new());
+        let config = BlockstoreCleanupConfig {
+            min_wall_interval: Duration::from_secs(30),
+            clock: clock.clone(),
+            ..BlockstoreCleanupConfig::default()
+        };
+        assert!(BlockstoreCleanupService::min_wall_interval_elapsed(
+            None, &config
+        ));
+        let last_purge_time = Some(clock.now());
+        clock.advance(Duration::from_secs(10));
+        assert!(!BlockstoreCleanupService::min_wall_interval_elapsed(
+            last_purge_time,
+            &config
+        ));
+        clock.advance(Duration::from_secs(20));
+        assert!(BlockstoreCleanupService::min_wall_interval_elapsed(
+            last_purge_time,
+            &config
+        ));
+    }
+
+    #[test]
+    fn test_projected_time_to_full() {
+        assert_eq!(
+            BlockstoreCleanupService::projected_time_to_full(1_000, 400, 10, 400),
+            Some(Duration::from_secs(24))
+        );
+        // Already at or over the limit
+        assert_eq!(
+            BlockstoreCleanupService::projected_time_to_full(1_000, 1_200, 10, 400),
+            Some(Duration::ZERO)
+        );
+        // Not growing
+        assert_eq!(
+            BlockstoreCleanupService::projected_time_to_full(1_000, 400, 0, 400),
+            None
+        );
+    }
+
+    #[test]
+    fn test_min_scan_interval_elapsed() {
+        let ledger_path = get_tmp_ledger_path_auto_delete!();
+        let blockstore = Blockstore::open(ledger_path.path()).unwrap();
+        let clock = Arc::new(ManualClock::new());
+        let mut config = BlockstoreCleanupConfig {
+            max_ledger_shreds: 100,
+            min_scan_interval: Duration::from_secs(30),
+            clock: clock.clone(),
+            ..BlockstoreCleanupConfig::default()
+        };
+        assert!(BlockstoreCleanupService::min_scan_interval_elapsed(
+            None,
+            &blockstore,
+            &config
+        ));
+        let last_scan_time = Some(clock.now());
+        clock.advance(Duration::from_secs(10));
+        assert!(!BlockstoreCleanupService::min_scan_interval_elapsed(
+            last_scan_time,
+            &blockstore,
+            &config
+        ));
+
+        // Within the interval, an estimate over the limit still allows a scan
+        config.shred_count_estimator = Some(Arc::new(FixedShredCount(Some(100))));
+        assert!(!BlockstoreCleanupService::min_scan_interval_elapsed(
+            last_scan_time,
+            &blockstore,
+            &config
+        ));
+        config.shred_count_estimator = Some(Arc::new(FixedShredCount(Some(101))));
+        assert!(BlockstoreCleanupService::min_scan_interval_elapsed(
+            last_scan_time,
+            &blockstore,
+            &config
+        ));
+
+        config.shred_count_estimator = None;
+        clock.advance(Duration::from_secs(20));
+        assert!(BlockstoreCleanupService::min_scan_interval_elapsed(
+            last_scan_time,
+            &blockstore,
+            &config
+        ));
+    }
+
+    #[test]
+    fn test_last_purge_slot_persistence() {
+        solana_logger::setup();
+        let ledger_path = get_tmp_ledger_path_auto_delete!();
+        let blockstore = Blockstore::open(ledger_path.path()).unwrap();
+        blockstore.set_roots([100].iter()).unwrap();
+
+        // Nothing is saved or loaded unless asked for
+        let mut config = BlockstoreCleanupConfig::default();
+        BlockstoreCleanupService::store_last_purge_slot(&blockstore, &config, 50);
+        assert!(!ledger_path.path().join(LAST_PURGE_SLOT_FILE).exists());
+        config.persist_last_purge_slot = true;
+        assert_eq!(
+            BlockstoreCleanupService::load_last_purge_slot(&blockstore, &config),
+            0
+        );
+
+        BlockstoreCleanupService::store_last_purge_slot(&blockstore, &config, 50);
+        assert_eq!(
+            BlockstoreCleanupService::load_last_purge_slot(&blockstore, &config),
+            50
+        );
+
+        // Slots above the root are from a different blockstore
+        BlockstoreCleanupService::store_last_purge_slot(&blockstore, &config, 150);
+        assert_eq!(
+            BlockstoreCleanupService::load_last_purge_slot(&blockstore, &config),
+            0
+        );
+
+        fs::write(ledger_path.path().join(LAST_PURGE_SLOT_FILE), "not a slot").unwrap();
+        assert_eq!(
+            BlockstoreCleanupService::load_last_purge_slot(&blockstore, &config),
+            0
+        );
+
+        BlockstoreCleanupService::store_last_purge_slot(&blockstore, &config, 50);
+        config.persist_last_purge_slot = false;
+        assert_eq!(
+            BlockstoreCleanupService::load_last_purge_slot(&blockstore, &config),
+            0
+        );
+    }
+
+    #[test]
+    fn test_health() {
+        solana_logger::setup();
+        let ledger_path = get_tmp_ledger_path_auto_delete!();
+        let blockstore = Arc::new(Blockstore::open(ledger_path.path()).unwrap());
+        let exit = Arc::new(AtomicBool::new(false));
+        let clock = Arc::new(ManualClock::new());
+        let config = BlockstoreCleanupConfig {
+            stall_threshold: Duration::from_secs(1),
+            clock: clock.clone(),
+            ..BlockstoreCleanupConfig::default()
+        };
+        let loop_limiter = config.loop_limiter();
+        let service = BlockstoreCleanupService::new_with_config(blockstore, config, exit.clone());
+        assert_eq!(service.health(), CleanupHealth::Healthy);
+        service.pause();
+        assert_eq!(service.health(), CleanupHealth::Paused);
+        service.resume();
+        assert_eq!(service.health(), CleanupHealth::Healthy);
+
+        // Stay short of the next check so that no cleanup completes
+        assert!(loop_limiter > Duration::from_secs(2));
+        clock.advance(Duration::from_secs(2));
+        assert_eq!(service.health(), CleanupHealth::Stalled);
+
+        exit.store(true, Ordering::Relaxed);
+        service.join().unwrap();
+    }
+
+    #[test]
+    fn test_health_stalled_by_veto() {
+        solana_logger::setup();
+        let ledger_path = get_tmp_ledger_path_auto_delete!();
+        let num_slots: u64 = 10;
+        let blockstore = setup_blockstore_with_slots(ledger_path.path(), num_slots);
+        blockstore.set_roots([num_slots].iter()).unwrap();
+
+        // Every purge is vetoed, and time passes while the veto is consulted
+        let clock = Arc::new(ManualClock::new());
+        let veto_clock = clock.clone();
+        let mut config = config_with_max_ledger_shreds(0);
+        config.purge_on_start = true;
+        config.stall_threshold = Duration::from_secs(1);
+        config.purge_veto = Some(Arc::new(move |_| {
+            veto_clock.advance(Duration::from_secs(2));
+            false
+        }));
+        config.clock = clock.clone();
+        let exit = Arc::new(AtomicBool::new(false));
+        let service = BlockstoreCleanupService::new_with_config(blockstore, config, exit.clone());
+
+        // The thread only sleeps once the cleanup on start is recorded
+        let start = Instant::now();
+        while clock.slept().is_zero() {
+            assert!(start.elapsed() < Duration::from_secs(10));
+            thread::sleep(Duration::from_millis(1));
+        }
+        assert_eq!(service.health(), CleanupHealth::Stalled);
+
+        exit.store(true, Ordering::Relaxed);
+        service.join().unwrap();
+    }
+
+    #[test]
+    fn test_calibrated_shred_size_estimator() {
+        let estimator = CalibratedShredSizeEstimator::new(2_000);
+        estimator.calibrate(1_000);
+        assert_eq!(estimator.bytes_per_shred(), 1_800);
+        estimator.calibrate(1_800);
+        assert_eq!(estimator.bytes_per_shred(), 1_800);
+        estimator.calibrate(2_800);
+        assert_eq!(estimator.bytes_per_shred(), 2_000);
+    }
+
+    #[test]
+    fn test_sample_bytes_per_shred() {
+        solana_logger::setup();
+        let ledger_path = get_tmp_ledger_path_auto_delete!();
+        let num_slots: u64 = 10;
+        let blockstore = setup_blockstore_with_slots(ledger_path.path(), num_slots);
+        assert_eq!(
+            BlockstoreCleanupService::sample_bytes_per_shred(&blockstore).unwrap(),
+            None
+        );
+
+        blockstore.set_roots([num_slots].iter()).unwrap();
+        let bytes_per_shred = BlockstoreCleanupService::sample_bytes_per_shred(&blockstore)
+            .unwrap()
+            .unwrap();
+        assert!(bytes_per_shred > 0);
+
+        let estimator = Arc::new(CalibratedShredSizeEstimator::new(0));
+        let config = BlockstoreCleanupConfig {
+            shred_size_estimator: Some(estimator.clone()),
+            ..BlockstoreCleanupConfig::default()
+        };
+        BlockstoreCleanupService::calibrate_shred_size(&blockstore, &config);
+        assert_eq!(
+            estimator.bytes_per_shred(),
+            (bytes_per_shred as f64 * SHRED_SIZE_CALIBRATION_SMOOTHING).round() as u64
+        );
+    }
+
+    #[test]
+    fn test_purge_backoffs() {
+        let config = BlockstoreCleanupConfig {
+            purge_base_backoff: Duration::from_millis(100),
+            purge_max_backoff: Duration::from_millis(800),
+            ..BlockstoreCleanupConfig::default()
+        };
+        assert_eq!(
+            config.purge_backoffs().collect::<Vec<_>>(),
+            [100, 200, 400, 800].map(Duration::from_millis),
+        );
+
+        let config = BlockstoreCleanupConfig {
+            purge_base_backoff: Duration::from_millis(100),
+            purge_max_backoff: Duration::from_millis(50),
+            ..BlockstoreCleanupConfig::default()
+        };
+        assert_eq!(config.purge_backoffs().count(), 0);
+
+        let config = BlockstoreCleanupConfig {
+            purge_base_backoff: Duration::ZERO,
+            ..BlockstoreCleanupConfig::default()
+        };
+        assert_eq!(config.purge_backoffs().count(), 0);
+    }
+
+    #[test]
+    fn test_retained_range() {
+        solana_logger::setup();
+        let ledger_path = get_tmp_ledger_path_auto_delete!();
+        let num_slots: u64 = 10;
+        let blockstore = setup_blockstore_with_slots(ledger_path.path(), num_slots);
+        let exit = Arc::new(AtomicBool::new(false));
+        let service = BlockstoreCleanupService::new(
+            blockstore.clone(),
+            DEFAULT_MAX_LEDGER_SHREDS,
+            exit.clone(),
+        );
+        assert_eq!(service.retained_range(&blockstore), None);
+
+        blockstore.set_roots([num_slots].iter()).unwrap();
+        assert_eq!(service.retained_range(&blockstore), Some((1, num_slots)));
+
+        BlockstoreCleanupService::purge_below(&blockstore, 5, PurgeType::Exact).unwrap();
+        assert_eq!(service.retained_range(&blockstore), Some((6, num_slots)));
+
+        BlockstoreCleanupService::purge_below(&blockstore, num_slots, PurgeType::Exact).unwrap();
+        assert_eq!(service.retained_range(&blockstore), None);
+
+        exit.store(true, Ordering::Relaxed);
+        service.join().unwrap();
+    }
+
+    #[test]
+    fn test_purge_range() {
+        solana_logger::setup();
+        let ledger_path = get_tmp_ledger_path_auto_delete!();
+        let num_slots: u64 = 10;
+        let blockstore = setup_blockstore_with_slots(ledger_path.path(), num_slots);
+        blockstore.set_roots([num_slots].iter()).unwrap();
+        let config = BlockstoreCleanupConfig::default();
+        let exit = AtomicBool::new(false);
+
+        // A range that leaves live slots below it is refused
+        BlockstoreCleanupService::purge_range(&blockstore, 4, 5, &config, &exit).unwrap();
+        assert!(blockstore.meta(4).unwrap().is_some());
+        assert!(blockstore.meta(5).unwrap().is_some());
+        assert_eq!(blockstore.lowest_cleanup_slot(), 0);
+        assert_eq!(blockstore.max_expired_slot(), None);
+
+        // A range that adjoins the cleaned up slots is purged like a cleanup
+        BlockstoreCleanupService::purge_range(&blockstore, 0, 2, &config, &exit).unwrap();
+        assert!(blockstore.meta(2).unwrap().is_none());
+        assert!(blockstore.meta(3).unwrap().is_some());
+        assert_eq!(blockstore.lowest_cleanup_slot(), 2);
+        assert_eq!(blockstore.max_expired_slot(), Some(2));
+
+        // A range that was already purged leaves the boundaries alone
+        BlockstoreCleanupService::purge_range(&blockstore, 1, 2, &config, &exit).unwrap();
+        assert_eq!(blockstore.lowest_cleanup_slot(), 2);
+        assert_eq!(blockstore.max_expired_slot(), Some(2));
+
+        // A range that reaches the root is refused
+        BlockstoreCleanupService::purge_range(&blockstore, 3, num_slots, &config, &exit).unwrap();
+        assert!(blockstore.meta(3).unwrap().is_some());
+        assert!(blockstore.meta(num_slots).unwrap().is_some());
+        assert_eq!(blockstore.lowest_cleanup_slot(), 2);
+
+        BlockstoreCleanupService::purge_range(&blockstore, 3, 5, &config, &exit).unwrap();
+        assert!(blockstore.meta(5).unwrap().is_none());
+        assert!(blockstore.meta(6).unwrap().is_some());
+        assert_eq!(blockstore.lowest_cleanup_slot(), 5);
+        assert_eq!(blockstore.max_expired_slot(), Some(5));
+    }
+
+    #[test]
+    fn test_scan_now() {
+        solana_logger::setup();
+        let ledger_path = get_tmp_ledger_path_auto_delete!();
+        let num_slots: u64 = 10;
+        let blockstore = setup_blockstore_with_slots(ledger_path.path(), num_slots);
+        blockstore.set_roots([num_slots].iter()).unwrap();
+        let exit = Arc::new(AtomicBool::new(false));
+        let mut config = config_with_max_ledger_shreds(0);
+        config.purge_interval = 1_000;
+        let service =
+            BlockstoreCleanupService::new_with_config(blockstore.clone(), config, exit.clone());
+
+        let SlotsToClean {
+            should_clean,
+            lowest_cleanup_slot,
+            total_shreds,
+            total_slots,
+            ..
+        } = service.scan_now(&blockstore);
+        assert!(should_clean);
+        assert_eq!(lowest_cleanup_slot, num_slots - 1);
+        assert!(total_shreds > 0);
+        assert_eq!(total_slots, num_slots);
+        // Nothing was purged
+        assert_eq!(service.last_purge_slot(), 0);
+        assert_eq!(blockstore.lowest_cleanup_slot(), 0);
+        assert!(blockstore.meta(1).unwrap().is_some());
+
+        exit.store(true, Ordering::Relaxed);
+        service.join().unwrap();
+    }
+
+    #[test]
+    fn test_request_cleanup_now() {
+        solana_logger::setup();
+        let ledger_path = get_tmp_ledger_path_auto_delete!();
+        let num_slots: u64 = 10;
+        let blockstore = setup_blockstore_with_slots(ledger_path.path(), num_slots);
+        blockstore.set_roots([num_slots].iter()).unwrap();
+        let exit = Arc::new(AtomicBool::new(false));
+        let mut config = config_with_max_ledger_shreds(0);
+        config.purge_interval = 1_000;
+        let clock = Arc::new(ManualClock::new());
+        config.clock = clock.clone();
+        let service =
+            BlockstoreCleanupService::new_with_config(blockstore.clone(), config, exit.clone());
+
+        // The request waits for the service to be resumed
+        service.pause();
+        service.request_cleanup_now();
+        clock.wait_for_check();
+        assert_eq!(blockstore.lowest_cleanup_slot(), 0);
+
+        service.resume();
+        let start = Instant::now();
+        while blockstore.lowest_cleanup_slot() == 0 {
+            assert!(start.elapsed() < Duration::from_secs(10));
+            thread::sleep(Duration::from_millis(1));
+        }
+        assert_eq!(blockstore.lowest_cleanup_slot(), num_slots - 1);
+
+        exit.store(true, Ordering::Relaxed);
+        service.join().unwrap();
+    }
+
+    #[test]
+    fn test_purge_range_now() {
+        solana_logger::setup();
+        let ledger_path = get_tmp_ledger_path_auto_delete!();
+        let num_slots: u64 = 10;
+        let blockstore = setup_blockstore_with_slots(ledger_path.path(), num_slots);
+        blockstore.set_roots([num_slots].iter()).unwrap();
+        let exit = Arc::new(AtomicBool::new(false));
+        let config = BlockstoreCleanupConfig {
+            exit_poll_interval: Duration::from_millis(1),
+            ..BlockstoreCleanupConfig::default()
+        };
+        let service =
+            BlockstoreCleanupService::new_with_config(blockstore.clone(), config, exit.clone());
+        service.pause();
+        service.purge_range_now(7, 3);
+        // Refused, since slots 0 through 4 haven't been cleaned up yet
+        service.purge_range_now(5, 7);
+        service.purge_range_now(0, 4);
+
+        let start = Instant::now();
+        while blockstore.meta(4).unwrap().is_some() {
+            assert!(start.elapsed() < Duration::from_secs(10));
+            thread::sleep(Duration::from_millis(1));
+        }
+        assert!(blockstore.meta(1).unwrap().is_none());
+        assert!(blockstore.meta(5).unwrap().is_some());
+        assert!(blockstore.meta(7).unwrap().is_some());
+        assert_eq!(blockstore.lowest_cleanup_slot(), 4);
+
+        exit.store(true, Ordering::Relaxed);
+        service.join().unwrap();
+    }
+
+    #[test]
+    fn test_join_timeout() {
+        solana_logger::setup();
+        let ledger_path = get_tmp_ledger_path_auto_delete!();
+        let blockstore = Arc::new(Blockstore::open(ledger_path.path()).unwrap());
+
+        let exit = Arc::new(AtomicBool::new(false));
+        let service = BlockstoreCleanupService::new(
+            blockstore.clone(),
+            DEFAULT_MAX_LEDGER_SHREDS,
+            exit.clone(),
+        );
+        exit.store(true, Ordering::Relaxed);
+        service.join_timeout(Duration::from_secs(60)).unwrap();
+
+        // The thread keeps running until the exit flag is set, so the
+        // timeout expires first and the thread is left detached
+        let exit = Arc::new(AtomicBool::new(false));
+        // The thread holds the only sender, so the channel disconnects once
+        // it has stopped
+        let (sender, receiver) = crossbeam_channel::bounded(1);
+        let config = BlockstoreCleanupConfig {
+            exit_poll_interval: Duration::from_millis(1),
+            purge_event_sender: Some(sender),
+            ..BlockstoreCleanupConfig::default()
+        };
+        let service = BlockstoreCleanupService::new_with_config(blockstore, config, exit.clone());
+        let start = Instant::now();
+        service.join_timeout(Duration::from_millis(10)).unwrap();
+        assert!(start.elapsed() < Duration::from_secs(10));
+
+        // Don't let the detached thread outlive the ledger directory
+        exit.store(true, Ordering::Relaxed);
+        assert_eq!(
+            receiver.recv_timeout(Duration::from_secs(10)),
+            Err(crossbeam_channel::RecvTimeoutError::Disconnected),
+        );
+    }
+
+    #[test]
+    fn test_cleanup_waits_for_loop_limiter() {
+        solana_logger::setup();
+        let ledger_path = get_tmp_ledger_path_auto_delete!();
+        let num_slots: u64 = 10;
+        let blockstore = setup_blockstore_with_slots(ledger_path.path(), num_slots);
+        blockstore.set_roots([num_slots].iter()).unwrap();
+
+        let clock = Arc::new(ManualClock::new());
+        let mut config = config_with_max_ledger_shreds(0);
+        config.purge_interval = 1;
+        config.clock = clock.clone();
+        let loop_limiter = config.loop_limiter();
+        assert!(loop_limiter > Duration::ZERO);
+        let exit = Arc::new(AtomicBool::new(false));
+        let service =
+            BlockstoreCleanupService::new_with_config(blockstore.clone(), config, exit.clone());
+
+        // The service polls repeatedly, but no cleanup is due until the clock
+        // has moved past the loop limiter
+        clock.advance(loop_limiter);
+        clock.wait_for_check();
+        assert_eq!(blockstore.lowest_cleanup_slot(), 0);
+
+        clock.advance(Duration::from_millis(1));
+        let start = Instant::now();
+        while blockstore.lowest_cleanup_slot() == 0 {
+            assert!(start.elapsed() < Duration::from_secs(10));
+            thread::sleep(Duration::from_millis(1));
+        }
+        exit.store(true, Ordering::Relaxed);
+        service.join().unwrap();
+        assert_eq!(blockstore.lowest_cleanup_slot(), num_slots - 1);
+    }
+
+    #[test]
+    fn test_exit_interrupts_throttled_purge() {
+        solana_logger::setup();
+        let ledger_path = get_tmp_ledger_path_auto_delete!();
+        let num_slots: u64 = 10;
+        let blockstore = setup_blockstore_with_slots(ledger_path.path(), num_slots);
+        blockstore.set_roots([num_slots].iter()).unwrap();
+
+        // At one byte per second, the purge would take practically forever
+        let mut config = config_with_max_ledger_shreds(0);
+        config.purge_interval = 0;
+        config.max_slots_per_purge = Some(1);
+        config.max_purge_bytes_per_sec = Some(1);
+        config.exit_poll_interval = Duration::from_millis(10);
+        let exit = Arc::new(AtomicBool::new(false));
+        let service =
+            BlockstoreCleanupService::new_with_config(blockstore.clone(), config, exit.clone());
+
+        let start = Instant::now();
+        while blockstore.lowest_cleanup_slot() == 0 {
+            assert!(start.elapsed() < Duration::from_secs(10));
+            thread::sleep(Duration::from_millis(1));
+        }
+        let exit_time = Instant::now();
+        exit.store(true, Ordering::Relaxed);
+        service.join().unwrap();
+        assert!(exit_time.elapsed() < Duration::from_secs(10));
+        assert!(blockstore.lowest_cleanup_slot() < num_slots - 1);
+        assert!(blockstore.meta(num_slots - 1).unwrap().is_some());
+    }
+
+    #[test]
+    fn test_cleanup_purge_on_start() {
+        solana_logger::setup();
+        let ledger_path = get_tmp_ledger_path_auto_delete!();
+        let num_slots: u64 = 10;
+        let blockstore = setup_blockstore_with_slots(ledger_path.path(), num_slots);
+        blockstore.set_roots([num_slots].iter()).unwrap();
+
+        // The clock never moves, so only the cleanup on start can happen
+        let mut config = config_with_max_ledger_shreds(0);
+        config.purge_on_start = true;
+        config.clock = Arc::new(ManualClock::new());
+        let exit = Arc::new(AtomicBool::new(false));
+        let service =
+            BlockstoreCleanupService::new_with_config(blockstore.clone(), config, exit.clone());
+
+        let start = Instant::now();
+        while service.last_purge_slot() != num_slots
+            || service.lowest_cleanup_slot() != num_slots - 1
+        {
+            assert!(start.elapsed() < Duration::from_secs(10));
+            thread::sleep(Duration::from_millis(1));
+        }
+        exit.store(true, Ordering::Relaxed);
+        service.join().unwrap();
+        for slot in 1..num_slots {
+            assert!(blockstore.meta(slot).unwrap().is_none());
+        }
+        assert!(blockstore.meta(num_slots).unwrap().is_some());
+    }
+
+    #[test]
+    fn test_join_waits_for_purge() {
+        solana_logger::setup();
+        let ledger_path = get_tmp_ledger_path_auto_delete!();
+        let num_slots: u64 = 10;
+        let blockstore = setup_blockstore_with_slots(ledger_path.path(), num_slots);
+        blockstore.set_roots([num_slots].iter()).unwrap();
+
+        let mut config = config_with_max_ledger_shreds(0);
+        config.purge_interval = 0;
+        config.max_slots_per_purge = Some(1);
+        config.exit_poll_interval = Duration::from_millis(10);
+        let exit = Arc::new(AtomicBool::new(false));
+        let service =
+            BlockstoreCleanupService::new_with_config(blockstore.clone(), config, exit.clone());
+
+        // Signal exit once the purge is underway
+        let start = Instant::now();
+        while blockstore.lowest_cleanup_slot() == 0 {
+            assert!(start.elapsed() < Duration::from_secs(10));
+            thread::sleep(Duration::from_millis(1));
+        }
+        exit.store(true, Ordering::Relaxed);
+        service.join().unwrap();
+
+        // The purge stopped at a step boundary, so everything up to the
+        // lowest cleanup slot is gone and everything above it remains
+        let lowest_cleanup_slot = blockstore.lowest_cleanup_slot();
+        for slot in 1..=lowest_cleanup_slot {
+            assert!(blockstore.meta(slot).unwrap().is_none());
+        }
+        for slot in lowest_cleanup_slot + 1..=num_slots {
+            assert!(blockstore.meta(slot).unwrap().is_some());
+        }
+    }
+
+    #[test]
+    fn test_cleanup() {
+        solana_logger::setup();
+        let ledger_path = get_tmp_ledger_path_auto_delete!();
+        let blockstore = Blockstore::open(ledger_path.path()).unwrap();
+        let (shreds, _) = make_many_slot_entries(0, 50, 5);
+        blockstore.insert_shreds(shreds, None, false).unwrap();
+
+        // Initiate a flush so inserted shreds found by find_slots_to_clean()
+        let blockstore = Arc::new(flush_blockstore_contents_to_disk(blockstore));
+
+        // Mark 50 as a root to kill all but 5 shreds, which will be in the newest slots
+        let mut last_purge_slot = 0;
+        blockstore.set_roots([50].iter()).unwrap();
+        BlockstoreCleanupService::cleanup_ledger(&blockstore, 5, &mut last_purge_slot, 10);
+        assert_eq!(last_purge_slot, 50);
+
+        //check that 0-40 don't exist
+        blockstore
+            .slot_meta_iterator(0)
+            .unwrap()
+            .for_each(|(slot, _)| assert!(slot > 40));
+    }
+
+    #[test]
+    fn test_cleanup_exact() {
+        solana_logger::setup();
+        let ledger_path = get_tmp_ledger_path_auto_delete!();
+        let blockstore = Blockstore::open(ledger_path.path()).unwrap();
+        let (shreds, _) = make_many_slot_entries(0, 50, 5);
+        blockstore.insert_shreds(shreds, None, false).unwrap();
+        let blockstore = Arc::new(flush_blockstore_contents_to_disk(blockstore));
+
+        // Same as test_cleanup(), but the purged slots are deleted outright
+        // instead of being left for the compaction filter
+        let mut last_purge_slot = 0;
+        blockstore.set_roots([50].iter()).unwrap();
+        let mut config = config_with_max_ledger_shreds(5);
+        config.purge_interval = 10;
+        config.purge_type = PurgeType::Exact;
+        let stats = BlockstoreCleanupService::cleanup_ledger_with_config(
+            &blockstore,
+            &config,
+            &mut last_purge_slot,
+            &AtomicBool::new(false),
+        );
+        assert!(stats.purged);
+        assert!(stats.lowest_cleanup_slot >= 40);
+        assert_eq!(blockstore.lowest_cleanup_slot(), stats.lowest_cleanup_slot);
+        assert_eq!(
+            blockstore.max_expired_slot(),
+            Some(stats.lowest_cleanup_slot)
+        );
+
+        for slot in 0..=40 {
+            assert!(blockstore.meta(slot).unwrap().is_none());
+            assert_eq!(blockstore.slot_data_iterator(slot, 0).unwrap().count(), 0);
+            assert_eq!(blockstore.slot_coding_iterator(slot, 0).unwrap().count(), 0);
+        }
+    }
+
+    #[test]
+    fn test_purge_once() {
+        solana_logger::setup();
+        let ledger_path = get_tmp_ledger_path_auto_delete!();
+        let num_slots: u64 = 10;
+        let blockstore = setup_blockstore_with_slots(ledger_path.path(), num_slots);
+
+        // Nothing is purged while under budget
+        let stats = BlockstoreCleanupService::purge_once(&blockstore, num_slots, u64::MAX).unwrap();
+        assert!(!stats.purged);
+        assert!(blockstore.meta(1).unwrap().is_some());
+
+        // The root bounds the purge, regardless of the blockstore's own root
+        let stats = BlockstoreCleanupService::purge_once(&blockstore, 5, 0).unwrap();
+        assert!(stats.purged);
+        assert_eq!(stats.lowest_cleanup_slot, 4);
+        assert_eq!(stats.limit, Some(RetentionLimit::Shreds));
+        assert_eq!(blockstore.lowest_cleanup_slot(), 4);
+        for slot in 1..=4 {
+            assert!(blockstore.meta(slot).unwrap().is_none());
+        }
+        for slot in 5..=num_slots {
+            assert!(blockstore.meta(slot).unwrap().is_some());
+        }
     }
 
     #[test]
@@ -1541,6 +1541,10 @@ impl Database {
         self.backend.oldest_slot.set(oldest_slot);
     }
 
+    pub fn oldest_slot(&self) -> Slot {
+        self.backend.oldest_slot.get()
+    }
+
     pub(crate) fn set_clean_slot_0(&self, clean_slot_0: bool) {
         self.backend.oldest_slot.set_clean_slot_0(clean_slot_0);
     }